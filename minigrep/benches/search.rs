@@ -0,0 +1,39 @@
+//! Compares the naive substring search of lines against the one used by `search`,
+//! which is SIMD accelerated when built with the `memchr` feature.
+//!
+//! Run it using `cargo bench -p minigrep --bench search --features memchr`.
+
+use std::time::{Duration, Instant};
+
+const ITERATIONS: u32 = 20;
+
+fn main() {
+    let line = "The quick brown fox jumps over the lazy dog, again and again. ";
+    let mut corpus = line.repeat(4).trim_end().to_owned() + "\n";
+    corpus = corpus.repeat(50_000);
+    corpus.push_str("a needle hidden in the haystack, found by a rather long query\n");
+    let query = "found by a rather long query";
+
+    let mut found = 0;
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        found += corpus.lines().filter(|line| line.contains(query)).count();
+    }
+    report("str::contains", start.elapsed(), found);
+
+    let mut found = 0;
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        found += minigrep::search(query, &corpus).len();
+    }
+    report("minigrep::search", start.elapsed(), found);
+}
+
+fn report(name: &str, elapsed: Duration, found: usize) {
+    println!(
+        "{}: {:?} per search ({} lines found in total)",
+        name,
+        elapsed / ITERATIONS,
+        found
+    );
+}
@@ -1,40 +1,373 @@
 use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::str::FromStr;
 
 use crate::error::Error;
 
+/// Format in which the matching lines are printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Lines as they are, prefixed by their file if more than one is searched.
+    Text,
+    /// `path,line_number,text` rows.
+    Csv,
+    /// `path:line_number:column:text` records, one for each match within a line,
+    /// with the 1-based column at which the match starts.
+    Positions,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<OutputFormat, Error> {
+        match s.to_lowercase().trim() {
+            "text" => Ok(OutputFormat::Text),
+            "csv" => Ok(OutputFormat::Csv),
+            "positions" => Ok(OutputFormat::Positions),
+            _ => Err(Error::InvalidArg("format")),
+        }
+    }
+}
+
+/// Bytes counted when only the amount of matched bytes is to be printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByteCount {
+    /// Bytes of the matches themselves.
+    Matches,
+    /// Bytes of the matching lines, excluding their line endings.
+    Lines,
+}
+
+impl FromStr for ByteCount {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ByteCount, Error> {
+        match s.to_lowercase().trim() {
+            "matches" => Ok(ByteCount::Matches),
+            "lines" => Ok(ByteCount::Lines),
+            _ => Err(Error::InvalidArg("byte-count")),
+        }
+    }
+}
+
+/// Unit in which the column of a match is counted, when printing match positions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnUnit {
+    Bytes,
+    Chars,
+}
+
+impl FromStr for ColumnUnit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ColumnUnit, Error> {
+        match s.to_lowercase().trim() {
+            "bytes" => Ok(ColumnUnit::Bytes),
+            "chars" => Ok(ColumnUnit::Chars),
+            _ => Err(Error::InvalidArg("column-unit")),
+        }
+    }
+}
+
 pub struct Config {
     query: String,
-    filename: String,
+    filenames: Vec<String>,
     case_insensitive: bool,
+    first_match_only: bool,
+    marker: Option<(String, String)>,
+    field: Option<usize>,
+    delimiter: String,
+    absolute_paths: bool,
+    group_by_file: bool,
+    format: OutputFormat,
+    column_unit: ColumnUnit,
+    hidden: bool,
+    unique: bool,
+    fail_fast: bool,
+    context_chars: Option<usize>,
+    histogram: Option<usize>,
+    streaming: bool,
+    byte_range: Option<(u64, u64)>,
+    byte_count: Option<ByteCount>,
+    invert_exit: bool,
+    sibling_files: Vec<String>,
+    stats: bool,
+    longest: bool,
 }
 
 impl Config {
-    pub fn from_args(mut args: env::Args) -> Result<Config, Error> {
+    pub fn new(query: &str, filenames: &[&str]) -> Config {
+        Config {
+            query: String::from(query),
+            filenames: filenames.iter().map(|f| String::from(*f)).collect(),
+            case_insensitive: false,
+            first_match_only: false,
+            marker: None,
+            field: None,
+            delimiter: String::from("\t"),
+            absolute_paths: false,
+            group_by_file: false,
+            format: OutputFormat::Text,
+            column_unit: ColumnUnit::Bytes,
+            hidden: false,
+            unique: false,
+            fail_fast: false,
+            context_chars: None,
+            histogram: None,
+            streaming: false,
+            byte_range: None,
+            byte_count: None,
+            invert_exit: false,
+            sibling_files: Vec::new(),
+            stats: false,
+            longest: false,
+        }
+    }
+
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> Result<Config, Error> {
         // skip program name
         args.next();
 
-        // read pos args
-        let query = args.next().ok_or(Error::MissingArg("query"))?;
-        let filename = args.next().ok_or(Error::MissingArg("filename"))?;
+        // read flags, leaving the pos args
+        let mut query_file = None;
+        let mut field = None;
+        let mut delimiter = String::from("\t");
+        let mut format = OutputFormat::Text;
+        let mut column_unit = ColumnUnit::Bytes;
+        let mut context_chars = None;
+        let mut histogram = None;
+        let mut byte_range = None;
+        let mut byte_count = None;
+        let mut sibling_files = Vec::new();
+        let mut pos_args = Vec::new();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--query-file" => {
+                    query_file = Some(args.next().ok_or(Error::MissingArg("query-file"))?);
+                }
+                "--field" => {
+                    let index = args.next().ok_or(Error::MissingArg("field"))?;
+                    field = Some(index.parse().map_err(|_| Error::InvalidArg("field"))?);
+                }
+                "--delimiter" => {
+                    delimiter = args.next().ok_or(Error::MissingArg("delimiter"))?;
+                }
+                "--format" => {
+                    format = args.next().ok_or(Error::MissingArg("format"))?.parse()?;
+                }
+                "--column-unit" => {
+                    column_unit = args
+                        .next()
+                        .ok_or(Error::MissingArg("column-unit"))?
+                        .parse()?;
+                }
+                "--context-chars" => {
+                    let n = args.next().ok_or(Error::MissingArg("context-chars"))?;
+                    context_chars =
+                        Some(n.parse().map_err(|_| Error::InvalidArg("context-chars"))?);
+                }
+                "--histogram" => {
+                    let width = args.next().ok_or(Error::MissingArg("histogram"))?;
+                    histogram = match width.parse() {
+                        Ok(0) | Err(_) => return Err(Error::InvalidArg("histogram")),
+                        Ok(width) => Some(width),
+                    };
+                }
+                "--byte-range" => {
+                    let range = args.next().ok_or(Error::MissingArg("byte-range"))?;
+                    byte_range = Some(parse_byte_range(&range)?);
+                }
+                "--byte-count" => {
+                    let count = args.next().ok_or(Error::MissingArg("byte-count"))?;
+                    byte_count = Some(count.parse()?);
+                }
+                "--sibling" => {
+                    sibling_files.push(args.next().ok_or(Error::MissingArg("sibling"))?);
+                }
+                _ => pos_args.push(arg),
+            }
+        }
+
+        // read pos args, the query is only expected when not read from a file
+        let mut pos_args = pos_args.into_iter();
+        let query = match query_file {
+            Some(path) => read_query_file(&path)?,
+            None => pos_args.next().ok_or(Error::MissingArg("query"))?,
+        };
+        let filenames: Vec<String> = pos_args.collect();
+        if filenames.is_empty() {
+            return Err(Error::MissingArg("filename"));
+        }
 
         // read env args
-        let case_insensitive = env::var("CASE_INSENSITIVE")
-            .map(|v| {
-                vec!["1", "true", "ok"]
-                    .iter()
-                    .any(|t| v.to_lowercase() == t.to_lowercase())
-            })
-            .unwrap_or(false);
+        let case_insensitive = env_flag("CASE_INSENSITIVE");
+        let first_match_only = env_flag("FIRST_MATCH_ONLY");
+        let absolute_paths = env_flag("ABSOLUTE_PATHS");
+        let group_by_file = env_flag("GROUP_BY_FILE");
+        let hidden = env_flag("HIDDEN");
+        let unique = env_flag("UNIQUE");
+        let fail_fast = env_flag("FAIL_FAST");
+        let streaming = env_flag("STREAMING");
+        let invert_exit = env_flag("INVERT_EXIT");
+        let stats = env_flag("STATS");
+        let longest = env_flag("LONGEST");
+        let marker = match (env::var("MARKER_START"), env::var("MARKER_END")) {
+            (Err(_), Err(_)) => None,
+            (start, end) => Some((start.unwrap_or_default(), end.unwrap_or_default())),
+        };
 
         Ok(Config {
             query,
-            filename,
+            filenames,
             case_insensitive,
+            first_match_only,
+            marker,
+            field,
+            delimiter,
+            absolute_paths,
+            group_by_file,
+            format,
+            column_unit,
+            hidden,
+            unique,
+            fail_fast,
+            context_chars,
+            histogram,
+            streaming,
+            byte_range,
+            byte_count,
+            invert_exit,
+            sibling_files,
+            stats,
+            longest,
         })
     }
 
-    pub fn filename(&self) -> &str {
-        self.filename.as_str()
+    pub fn with_case_insensitive(self, case_insensitive: bool) -> Config {
+        Config {
+            case_insensitive,
+            ..self
+        }
+    }
+
+    pub fn with_first_match_only(self, first_match_only: bool) -> Config {
+        Config {
+            first_match_only,
+            ..self
+        }
+    }
+
+    pub fn with_marker(self, start: &str, end: &str) -> Config {
+        Config {
+            marker: Some((String::from(start), String::from(end))),
+            ..self
+        }
+    }
+
+    pub fn with_field(self, field: usize, delimiter: &str) -> Config {
+        Config {
+            field: Some(field),
+            delimiter: String::from(delimiter),
+            ..self
+        }
+    }
+
+    pub fn with_absolute_paths(self, absolute_paths: bool) -> Config {
+        Config {
+            absolute_paths,
+            ..self
+        }
+    }
+
+    pub fn with_group_by_file(self, group_by_file: bool) -> Config {
+        Config {
+            group_by_file,
+            ..self
+        }
+    }
+
+    pub fn with_format(self, format: OutputFormat) -> Config {
+        Config { format, ..self }
+    }
+
+    pub fn with_column_unit(self, column_unit: ColumnUnit) -> Config {
+        Config {
+            column_unit,
+            ..self
+        }
+    }
+
+    pub fn with_hidden(self, hidden: bool) -> Config {
+        Config { hidden, ..self }
+    }
+
+    pub fn with_unique(self, unique: bool) -> Config {
+        Config { unique, ..self }
+    }
+
+    pub fn with_fail_fast(self, fail_fast: bool) -> Config {
+        Config { fail_fast, ..self }
+    }
+
+    pub fn with_context_chars(self, n: usize) -> Config {
+        Config {
+            context_chars: Some(n),
+            ..self
+        }
+    }
+
+    pub fn with_streaming(self, streaming: bool) -> Config {
+        Config { streaming, ..self }
+    }
+
+    pub fn with_byte_range(self, start: u64, end: u64) -> Config {
+        Config {
+            byte_range: Some((start, end)),
+            ..self
+        }
+    }
+
+    pub fn with_invert_exit(self, invert_exit: bool) -> Config {
+        Config {
+            invert_exit,
+            ..self
+        }
+    }
+
+    pub fn with_sibling_files(self, sibling_files: &[&str]) -> Config {
+        Config {
+            sibling_files: sibling_files.iter().map(|f| String::from(*f)).collect(),
+            ..self
+        }
+    }
+
+    pub fn with_stats(self, stats: bool) -> Config {
+        Config { stats, ..self }
+    }
+
+    pub fn with_longest(self, longest: bool) -> Config {
+        Config { longest, ..self }
+    }
+
+    pub fn with_byte_count(self, byte_count: ByteCount) -> Config {
+        Config {
+            byte_count: Some(byte_count),
+            ..self
+        }
+    }
+
+    /// Panics if the bucket width is 0.
+    pub fn with_histogram(self, bucket_width: usize) -> Config {
+        assert!(bucket_width > 0, "histogram bucket width must be positive");
+        Config {
+            histogram: Some(bucket_width),
+            ..self
+        }
+    }
+
+    pub fn filenames(&self) -> &[String] {
+        &self.filenames
     }
 
     pub fn query(&self) -> &str {
@@ -44,4 +377,164 @@ impl Config {
     pub fn case_insensitive(&self) -> bool {
         self.case_insensitive
     }
+
+    /// Stop searching altogether after the first matching line,
+    /// no matter in which file it was found.
+    pub fn first_match_only(&self) -> bool {
+        self.first_match_only
+    }
+
+    /// Plain-text markers to wrap each match with, within the printed lines.
+    pub fn marker(&self) -> Option<(&str, &str)> {
+        self.marker
+            .as_ref()
+            .map(|(start, end)| (start.as_str(), end.as_str()))
+    }
+
+    /// Print only this (1-based) field of a matching line, like `cut` would,
+    /// printing nothing for that line when the line has no such field.
+    pub fn field(&self) -> Option<usize> {
+        self.field
+    }
+
+    /// Print the paths of files with matches in their absolute form,
+    /// or as found when that form cannot be resolved.
+    pub fn absolute_paths(&self) -> bool {
+        self.absolute_paths
+    }
+
+    /// Print the matching lines of a file indented under a header with its path,
+    /// instead of prefixing each line with that path. The header is printed once,
+    /// as soon as the first match of a file is found, such that it composes with streaming.
+    pub fn group_by_file(&self) -> bool {
+        self.group_by_file
+    }
+
+    pub fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    /// Unit in which the columns of matches are counted, bytes by default.
+    pub fn column_unit(&self) -> ColumnUnit {
+        self.column_unit
+    }
+
+    /// Search hidden files and directories, those starting with a `.`,
+    /// when found within a searched directory.
+    pub fn hidden(&self) -> bool {
+        self.hidden
+    }
+
+    /// Print each distinct matching line only once, the first time it is found.
+    pub fn unique(&self) -> bool {
+        self.unique
+    }
+
+    /// Stop searching at the first file found within a directory which cannot be read,
+    /// instead of skipping it and reporting it once the search is done.
+    /// A file given explicitly which cannot be read always stops the search.
+    pub fn fail_fast(&self) -> bool {
+        self.fail_fast
+    }
+
+    /// Print only the parts of a matching line within this many chars of a match,
+    /// with ellipses marking the parts left out.
+    pub fn context_chars(&self) -> Option<usize> {
+        self.context_chars
+    }
+
+    /// Print a histogram of the lengths of the matching lines,
+    /// in buckets of this many chars, instead of the lines themselves.
+    pub fn histogram(&self) -> Option<usize> {
+        self.histogram
+    }
+
+    /// Read files line by line as they are searched, printing each match as soon as it is found,
+    /// instead of reading each file as a whole first. A file which turns out to be unreadable
+    /// halfway is handled as any other unreadable file, after its earlier matches were printed.
+    pub fn streaming(&self) -> bool {
+        self.streaming
+    }
+
+    /// Search only the lines starting within this `[start, end)` byte range of each file,
+    /// such that adjacent ranges search each line exactly once. A line starting in the range
+    /// is searched as a whole, even when it ends past it. Line numbers are counted
+    /// from the first line searched.
+    pub fn byte_range(&self) -> Option<(u64, u64)> {
+        self.byte_range
+    }
+
+    /// Print the total amount of matched bytes, counted as selected,
+    /// instead of the matching lines.
+    pub fn byte_count(&self) -> Option<ByteCount> {
+        self.byte_count
+    }
+
+    /// Exit successfully only when no lines matched, for asserting a query is absent,
+    /// without changing which lines are printed.
+    pub fn invert_exit(&self) -> bool {
+        self.invert_exit
+    }
+
+    /// Files of which the line with the same number as a matching line is printed
+    /// right after it, when present, to compare the matching lines with these files.
+    pub fn sibling_files(&self) -> &[String] {
+        &self.sibling_files
+    }
+
+    /// Report the amount of bytes searched, the time it took and the resulting throughput
+    /// once the search is done, to stderr, in total over all searched files.
+    pub fn stats(&self) -> bool {
+        self.stats
+    }
+
+    /// Print only the longest matching line, in chars, prefixed by its file and line number,
+    /// instead of all matching lines. Of equally long lines the first one found is printed.
+    pub fn longest(&self) -> bool {
+        self.longest
+    }
+
+    /// Delimiter used to split a matching line into fields, a tab by default.
+    pub fn delimiter(&self) -> &str {
+        self.delimiter.as_str()
+    }
+}
+
+// Parse a `start:end` byte range.
+fn parse_byte_range(range: &str) -> Result<(u64, u64), Error> {
+    let (start, end) = range
+        .split_once(':')
+        .ok_or(Error::InvalidArg("byte-range"))?;
+    match (start.parse(), end.parse()) {
+        (Ok(start), Ok(end)) if start <= end => Ok((start, end)),
+        _ => Err(Error::InvalidArg("byte-range")),
+    }
+}
+
+// Read the query verbatim from a file, or stdin when the path is "-",
+// only trimming the trailing newline.
+fn read_query_file(path: &str) -> Result<String, Error> {
+    let mut query = String::new();
+    if path == "-" {
+        io::stdin().read_to_string(&mut query)?;
+    } else {
+        query = fs::read_to_string(path)?;
+    }
+    if query.ends_with('\n') {
+        query.pop();
+        if query.ends_with('\r') {
+            query.pop();
+        }
+    }
+    Ok(query)
+}
+
+fn env_flag(key: &str) -> bool {
+    env::var(key)
+        .map(|v| {
+            ["1", "true", "ok"]
+                .iter()
+                .any(|t| v.to_lowercase() == t.to_lowercase())
+        })
+        .unwrap_or(false)
 }
@@ -1,9 +1,10 @@
+use std::fmt;
 use std::io;
-// use std::fmt;
 
 #[derive(Debug, Clone)]
 pub enum Error {
     MissingArg(&'static str),
+    InvalidArg(&'static str),
     IO(String),
     Runtime(String),
     NoResults,
@@ -12,17 +13,19 @@ pub enum Error {
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
         Error::IO(match err.kind() {
-            io::ErrorKind::NotFound => format!("file not found: {}", err.to_string()),
-            _ => format!("unexpected IO Error: {}", err.to_string()),
+            io::ErrorKind::NotFound => format!("file not found: {}", err),
+            _ => format!("unexpected IO Error: {}", err),
         })
     }
 }
 
-// impl fmt::Display for Error {
-//     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-//         write!(f, "{}", match self {
-//             Error::MissingArg(ref s) => s,
-//             Error::IOError(s) => s.as_str(),
-//         })
-//     }
-// }
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MissingArg(arg) => write!(f, "missing argument: {}", arg),
+            Error::InvalidArg(arg) => write!(f, "invalid argument: {}", arg),
+            Error::IO(s) | Error::Runtime(s) => f.write_str(s),
+            Error::NoResults => f.write_str("no results found"),
+        }
+    }
+}
@@ -1,55 +1,570 @@
-use std::fs;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 mod config;
 mod error;
+mod walk;
 
-pub use config::Config;
+use walk::Entry;
+
+pub use config::{ByteCount, ColumnUnit, Config, OutputFormat};
 pub use error::Error;
 
 pub fn run(cfg: Config) -> Result<(), Error> {
-    // read file
-    let contents = fs::read_to_string(cfg.filename())?;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    run_with_writers(&cfg, &mut out, &mut io::stderr())
+}
+
+/// Map the result of a search to the exit code `grep` would exit with:
+/// `0` when lines matched, `1` when none did and `2` when an error occurred,
+/// which includes not matching any line while files were skipped as unreadable.
+///
+/// When inverted the first two are swapped, exiting with `0` only when no lines matched,
+/// while an error still exits with `2`.
+pub fn exit_code(result: &Result<(), Error>, invert_exit: bool) -> i32 {
+    match (result, invert_exit) {
+        (Ok(()), false) | (Err(Error::NoResults), true) => 0,
+        (Ok(()), true) | (Err(Error::NoResults), false) => 1,
+        (Err(_), _) => 2,
+    }
+}
+
+pub fn run_with_writer(cfg: &Config, out: &mut impl Write) -> Result<(), Error> {
+    run_with_writers(cfg, out, &mut io::stderr())
+}
+
+pub fn run_with_writers(
+    cfg: &Config,
+    out: &mut impl Write,
+    err: &mut impl Write,
+) -> Result<(), Error> {
+    let start = Instant::now();
+    let scanned = Cell::new(0);
 
-    // define search func
-    let search = if cfg.case_insensitive() {
-        search_case_insensitive
+    // define the predicate matching a line against the query
+    let query = if cfg.case_insensitive() {
+        cfg.query().to_lowercase()
     } else {
-        search
+        String::from(cfg.query())
     };
+    let contains_query = substring_matcher(&query);
+    let is_match = |line: &str| {
+        if cfg.case_insensitive() {
+            contains_query(&line.to_lowercase())
+        } else {
+            contains_query(line)
+        }
+    };
+
+    // read the files to print the lines of next to matching lines upfront, once
+    let siblings = cfg
+        .sibling_files()
+        .iter()
+        .map(|path| {
+            let contents = fs::read_to_string(path)?;
+            Ok((path, contents.lines().map(String::from).collect::<Vec<_>>()))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
 
-    // search the query for each read line
+    // prefix lines with their file when searching more than one
+    let multi_file =
+        cfg.filenames().len() > 1 || cfg.filenames().iter().any(|f| Path::new(f).is_dir());
+
+    // search the query for each read line, of each file
     let mut lines_found = 0;
-    for line in search(cfg.query(), &contents) {
-        println!("{}", line);
-        lines_found += 1;
+    let mut groups = 0;
+    let mut seen = HashSet::new();
+    let mut skipped = Vec::new();
+    let mut lengths = Vec::new();
+    let mut longest: Option<(usize, PathBuf, usize, String)> = None;
+    let mut bytes = 0;
+    let mut visit = |entry: Entry| {
+        let (path, reader) = match entry {
+            Entry::File(path) => (path, open(cfg, path)),
+            Entry::Unreadable(path, e) => (path, Err(e)),
+        };
+        // only files found within a directory are skipped,
+        // those given explicitly are expected to be searched
+        let explicit = cfg.filenames().iter().any(|f| Path::new(f) == path);
+        let reader = match reader {
+            Ok(reader) => CountingReader {
+                inner: reader,
+                count: &scanned,
+            },
+            Err(e) if cfg.fail_fast() || explicit => return Err(e),
+            Err(e) => {
+                skipped.push((path.to_path_buf(), e));
+                return Ok(true);
+            }
+        };
+        let path = if cfg.absolute_paths() {
+            fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+        } else {
+            path.to_path_buf()
+        };
+        let mut grouped = false;
+        for (index, line) in reader.lines().enumerate() {
+            let line = match line {
+                Ok(line) => line,
+                // only possible when streaming, as otherwise the file was read upfront
+                Err(e) if cfg.fail_fast() || explicit => return Err(Error::from(e)),
+                Err(e) => {
+                    skipped.push((path.to_path_buf(), Error::from(e)));
+                    return Ok(true);
+                }
+            };
+            let line = line.as_str();
+            if !is_match(line) {
+                continue;
+            }
+            if cfg.unique() && !seen.insert(String::from(line)) {
+                continue;
+            }
+            lines_found += 1;
+            if let Some(byte_count) = cfg.byte_count() {
+                bytes += match byte_count {
+                    ByteCount::Matches => match_spans(cfg.query(), line, cfg.case_insensitive())
+                        .iter()
+                        .map(|(start, end)| end - start)
+                        .sum(),
+                    ByteCount::Lines => line.len(),
+                };
+            } else if cfg.histogram().is_some() {
+                lengths.push(line.chars().count());
+            } else if cfg.longest() {
+                let len = line.chars().count();
+                if longest.as_ref().is_none_or(|(max, ..)| len > *max) {
+                    longest = Some((len, path.clone(), index + 1, String::from(line)));
+                }
+            } else if cfg.format() == OutputFormat::Positions {
+                if let Some(text) = format_line(cfg, line) {
+                    write_positions(out, cfg, &path, index + 1, line, &text)?;
+                }
+            } else if let Some(line) = format_line(cfg, line) {
+                if cfg.format() == OutputFormat::Csv {
+                    writeln!(
+                        out,
+                        "{},{},{}",
+                        csv_field(&path.display().to_string()),
+                        index + 1,
+                        csv_field(&line)
+                    )?;
+                    for (sibling, lines) in &siblings {
+                        if let Some(line) = lines.get(index) {
+                            writeln!(
+                                out,
+                                "{},{},{}",
+                                csv_field(sibling),
+                                index + 1,
+                                csv_field(line)
+                            )?;
+                        }
+                    }
+                } else if cfg.group_by_file() {
+                    if !grouped {
+                        // separate groups by a blank line
+                        if groups > 0 {
+                            writeln!(out)?;
+                        }
+                        writeln!(out, "{}", path.display())?;
+                        groups += 1;
+                        grouped = true;
+                    }
+                    writeln!(out, "  {}", line)?;
+                    write_sibling_lines(out, &siblings, index, "    ")?;
+                } else if multi_file {
+                    writeln!(out, "{}:{}", path.display(), line)?;
+                    write_sibling_lines(out, &siblings, index, "  ")?;
+                } else {
+                    writeln!(out, "{}", line)?;
+                    write_sibling_lines(out, &siblings, index, "  ")?;
+                }
+            }
+            if cfg.first_match_only() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    };
+    for filename in cfg.filenames() {
+        if !walk::walk(Path::new(filename), cfg.hidden(), &mut visit)? {
+            break;
+        }
+    }
+
+    if cfg.byte_count().is_some() {
+        writeln!(out, "{}", bytes)?;
+    } else if let Some(bucket_width) = cfg.histogram() {
+        write_histogram(out, &lengths, bucket_width)?;
+    } else if let Some((_, path, line_number, line)) = &longest {
+        if let Some(line) = format_line(cfg, line) {
+            writeln!(out, "{}:{}:{}", path.display(), line_number, line)?;
+        }
+    }
+
+    if cfg.stats() {
+        write_stats(err, scanned.get(), start.elapsed().as_secs_f64())?;
     }
 
-    // ensure we return an error if nothing was found
+    // report what could not be searched
+    if !skipped.is_empty() {
+        writeln!(err, "skipped {} unreadable file(s):", skipped.len())?;
+        for (path, e) in &skipped {
+            writeln!(err, "  {}: {}", path.display(), e)?;
+        }
+    }
+
+    // ensure we return an error if nothing was found,
+    // which isn't conclusive when some files could not be searched
     if lines_found > 0 {
         Ok(())
+    } else if !skipped.is_empty() {
+        Err(Error::IO(format!(
+            "skipped {} unreadable file(s)",
+            skipped.len()
+        )))
     } else {
         Err(Error::NoResults)
     }
 }
 
+// Write the line with the given index of each sibling file having it,
+// indented below the matching line.
+fn write_sibling_lines(
+    out: &mut impl Write,
+    siblings: &[(&String, Vec<String>)],
+    index: usize,
+    indent: &str,
+) -> io::Result<()> {
+    for (sibling, lines) in siblings {
+        if let Some(line) = lines.get(index) {
+            writeln!(out, "{}{}:{}", indent, sibling, line)?;
+        }
+    }
+    Ok(())
+}
+
+// Write a `path:line_number:column:text` record for each match within the line.
+fn write_positions(
+    out: &mut impl Write,
+    cfg: &Config,
+    path: &Path,
+    line_number: usize,
+    line: &str,
+    text: &str,
+) -> io::Result<()> {
+    for (start, _) in match_spans(cfg.query(), line, cfg.case_insensitive()) {
+        let column = match cfg.column_unit() {
+            ColumnUnit::Bytes => start + 1,
+            ColumnUnit::Chars => line[..start].chars().count() + 1,
+        };
+        writeln!(
+            out,
+            "{}:{}:{}:{}",
+            path.display(),
+            line_number,
+            column,
+            text
+        )?;
+    }
+    Ok(())
+}
+
+// Reader counting the bytes read through it.
+struct CountingReader<'a, R> {
+    inner: R,
+    count: &'a Cell<u64>,
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.count.set(self.count.get() + amt as u64);
+        self.inner.consume(amt);
+    }
+}
+
+// Print the amount of bytes searched, the time it took and the resulting throughput.
+fn write_stats(err: &mut impl Write, bytes: u64, secs: f64) -> io::Result<()> {
+    let throughput = if secs > 0.0 {
+        bytes as f64 / 1_000_000.0 / secs
+    } else {
+        0.0
+    };
+    writeln!(
+        err,
+        "searched {} bytes in {:.3}s ({:.2} MB/s)",
+        bytes, secs, throughput
+    )
+}
+
+// Open a file for searching, reading it line by line when streaming,
+// or reading it upfront as a whole otherwise.
+fn open(cfg: &Config, path: &Path) -> Result<Box<dyn BufRead>, Error> {
+    if let Some((start, end)) = cfg.byte_range() {
+        Ok(Box::new(io::Cursor::new(read_byte_range(
+            path, start, end,
+        )?)))
+    } else if cfg.streaming() {
+        Ok(Box::new(BufReader::new(File::open(path)?)))
+    } else {
+        Ok(Box::new(io::Cursor::new(fs::read_to_string(path)?)))
+    }
+}
+
+// Read the lines starting within the `[start, end)` byte range of a file.
+fn read_byte_range(path: &Path, start: u64, end: u64) -> Result<String, Error> {
+    let mut file = File::open(path)?;
+    // a line is part of the range it starts in
+    let mut in_line = false;
+    if start > 0 {
+        file.seek(SeekFrom::Start(start - 1))?;
+        let mut previous = [0];
+        in_line = file.read(&mut previous)? == 1 && previous[0] != b'\n';
+    }
+    let mut reader = BufReader::new(file);
+    let mut offset = start;
+    if in_line {
+        offset += reader.read_until(b'\n', &mut Vec::new())? as u64;
+    }
+    let mut lines = String::new();
+    while offset < end {
+        match reader.read_line(&mut lines)? {
+            0 => break,
+            n => offset += n as u64,
+        }
+    }
+    Ok(lines)
+}
+
+// Print the amount of lengths within each bucket, from the shortest to the longest
+// bucket with lengths, as a row per bucket with a bar of (at most 50) '#'.
+fn write_histogram(
+    out: &mut impl Write,
+    lengths: &[usize],
+    bucket_width: usize,
+) -> Result<(), Error> {
+    let mut buckets = BTreeMap::new();
+    for length in lengths {
+        *buckets.entry(length / bucket_width).or_insert(0) += 1;
+    }
+    let (first, last) = match (buckets.keys().next(), buckets.keys().next_back()) {
+        (Some(first), Some(last)) => (*first, *last),
+        _ => return Ok(()),
+    };
+    let max = buckets.values().copied().max().unwrap_or(0);
+    let label_width = ((last + 1) * bucket_width - 1).to_string().len();
+    for bucket in first..=last {
+        let count = buckets.get(&bucket).copied().unwrap_or(0);
+        let bar = (count * HISTOGRAM_BAR_WIDTH).div_ceil(max.max(HISTOGRAM_BAR_WIDTH));
+        writeln!(
+            out,
+            "{:>w$}-{:<w$} | {} {}",
+            bucket * bucket_width,
+            (bucket + 1) * bucket_width - 1,
+            "#".repeat(bar),
+            count,
+            w = label_width
+        )?;
+    }
+    Ok(())
+}
+
+const HISTOGRAM_BAR_WIDTH: usize = 50;
+
 pub fn search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
+    let contains_query = substring_matcher(query);
     contents
         .lines()
-        .filter(|line| line.contains(query))
+        .filter(|line| contains_query(line))
         .collect()
 }
 
 pub fn search_case_insensitive<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
     let query = query.to_lowercase();
+    let contains_query = substring_matcher(&query);
     contents
         .lines()
-        .filter(|line| line.to_lowercase().contains(&query))
+        .filter(|line| contains_query(&line.to_lowercase()))
         .collect()
 }
 
+// Create a predicate checking whether a line contains the query,
+// using a SIMD accelerated search when built with the `memchr` feature.
+#[cfg(feature = "memchr")]
+fn substring_matcher(query: &str) -> impl Fn(&str) -> bool + '_ {
+    let finder = memchr::memmem::Finder::new(query);
+    move |line| finder.find(line.as_bytes()).is_some()
+}
+
+#[cfg(not(feature = "memchr"))]
+fn substring_matcher(query: &str) -> impl Fn(&str) -> bool + '_ {
+    move |line| line.contains(query)
+}
+
+// Format a matching line as it is to be printed,
+// or None if nothing is to be printed for it.
+fn format_line(cfg: &Config, line: &str) -> Option<String> {
+    let line = match cfg.field() {
+        Some(field) => line.split(cfg.delimiter()).nth(field.checked_sub(1)?)?,
+        None => line,
+    };
+    if cfg.marker().is_none() && cfg.context_chars().is_none() {
+        return Some(String::from(line));
+    }
+
+    // only print the parts of the line surrounding the matches if desired
+    let spans = match_spans(cfg.query(), line, cfg.case_insensitive());
+    let windows = match cfg.context_chars() {
+        Some(n) if !spans.is_empty() => context_windows(line, &spans, n),
+        _ => vec![(0, line.len())],
+    };
+
+    let mut result = String::with_capacity(line.len());
+    let mut end = 0;
+    for (from, to) in windows {
+        if from > end {
+            result += "...";
+        }
+        let window = &line[from..to];
+        match cfg.marker() {
+            Some((start, stop)) => {
+                let spans: Vec<(usize, usize)> = spans
+                    .iter()
+                    .filter(|(a, b)| *a >= from && *b <= to)
+                    .map(|(a, b)| (a - from, b - from))
+                    .collect();
+                result += &highlight(window, &spans, start, stop);
+            }
+            None => result += window,
+        }
+        end = to;
+    }
+    if end < line.len() {
+        result += "...";
+    }
+    Some(result)
+}
+
+// Compute the byte ranges of the line within n chars of a match,
+// merging those which overlap.
+fn context_windows(line: &str, spans: &[(usize, usize)], n: usize) -> Vec<(usize, usize)> {
+    let mut windows: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in spans {
+        let from = match n {
+            0 => *start,
+            _ => line[..*start]
+                .char_indices()
+                .rev()
+                .nth(n - 1)
+                .map_or(0, |(i, _)| i),
+        };
+        let to = line[*end..]
+            .char_indices()
+            .nth(n)
+            .map_or(line.len(), |(i, _)| end + i);
+        match windows.last_mut() {
+            Some(last) if from <= last.1 => last.1 = last.1.max(to),
+            _ => windows.push((from, to)),
+        }
+    }
+    windows
+}
+
+// Quote a CSV field if it contains a delimiter, quote or newline,
+// escaping the quotes within it.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        String::from(field)
+    }
+}
+
+/// Find the byte ranges of all non-overlapping occurrences of the query within the line.
+pub fn match_spans(query: &str, line: &str, case_insensitive: bool) -> Vec<(usize, usize)> {
+    if query.is_empty() {
+        return Vec::new();
+    }
+    if !case_insensitive {
+        return line
+            .match_indices(query)
+            .map(|(start, m)| (start, start + m.len()))
+            .collect();
+    }
+
+    // compare char by char, as lowercasing can change the byte length of the line
+    let query: Vec<char> = query.chars().flat_map(char::to_lowercase).collect();
+    let mut spans = Vec::new();
+    let mut start = 0;
+    while let Some(c) = line[start..].chars().next() {
+        match match_len_case_insensitive(&query, &line[start..]) {
+            Some(len) => {
+                spans.push((start, start + len));
+                start += len;
+            }
+            None => start += c.len_utf8(),
+        }
+    }
+    spans
+}
+
+fn match_len_case_insensitive(query: &[char], s: &str) -> Option<usize> {
+    let mut query = query.iter();
+    for (i, c) in s.char_indices() {
+        for lc in c.to_lowercase() {
+            if query.next() != Some(&lc) {
+                return None;
+            }
+        }
+        if query.len() == 0 {
+            return Some(i + c.len_utf8());
+        }
+    }
+    None
+}
+
+fn highlight(line: &str, spans: &[(usize, usize)], start: &str, end: &str) -> String {
+    let mut result = String::with_capacity(line.len());
+    let mut offset = 0;
+    for (from, to) in spans {
+        result += &line[offset..*from];
+        result += start;
+        result += &line[*from..*to];
+        result += end;
+        offset = *to;
+    }
+    result += &line[offset..];
+    result
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("minigrep-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
 
     #[test]
     fn one_result() {
@@ -62,6 +577,35 @@ Pick three.";
         assert_eq!(vec!["safe, fast, productive."], search(query, contents));
     }
 
+    #[test]
+    fn substring_matcher_matches_naive_search() {
+        let contents = "\
+Rust:
+safe, fast, productive.
+Pick three.
+Trust me.
+rust rust rust
+aaaaaaaaaaaaaaaaaaaaaaab
+Ünïcödé rüst
+";
+        for query in [
+            "",
+            "rust",
+            "st",
+            "aab",
+            "aaaab",
+            "rüst",
+            "ü",
+            "productive.",
+            "none",
+        ] {
+            let contains_query = substring_matcher(query);
+            let expected: Vec<&str> = contents.lines().filter(|l| l.contains(query)).collect();
+            let found: Vec<&str> = contents.lines().filter(|l| contains_query(l)).collect();
+            assert_eq!(expected, found, "query: {:?}", query);
+        }
+    }
+
     #[test]
     fn case_insensitive() {
         let query = "rUsT";
@@ -76,4 +620,623 @@ Trust me.";
             search_case_insensitive(query, contents)
         );
     }
+
+    #[test]
+    fn first_match_only() {
+        let dir = temp_dir("first-match-only");
+        fs::write(
+            dir.join("a.txt"),
+            "safe, fast, productive.\nTrust me.\nRust!",
+        )
+        .unwrap();
+        fs::write(dir.join("b.txt"), "Trust the process.").unwrap();
+        // never reached as the walk stops at the first match
+        let missing = dir.join("missing.txt");
+
+        let dir_str = dir.to_str().unwrap();
+        let cfg = Config::new("rust", &[dir_str, missing.to_str().unwrap()])
+            .with_case_insensitive(true)
+            .with_first_match_only(true);
+        let mut out = Vec::new();
+        run_with_writer(&cfg, &mut out).unwrap();
+        assert_eq!(
+            format!("{}:Trust me.\n", dir.join("a.txt").display()),
+            String::from_utf8(out).unwrap(),
+        );
+
+        let cfg = cfg.with_first_match_only(false);
+        assert!(matches!(
+            run_with_writer(&cfg, &mut Vec::new()),
+            Err(Error::IO(_))
+        ));
+    }
+
+    #[test]
+    fn case_insensitive_match_spans() {
+        assert_eq!(
+            vec![(0, 4), (8, 12)],
+            match_spans("rUsT", "Rust & trust", true)
+        );
+        assert_eq!(vec![(8, 12)], match_spans("rust", "Rust & trust", false));
+    }
+
+    #[test]
+    fn marker() {
+        let dir = temp_dir("marker");
+        let filename = dir.join("poem.txt");
+        fs::write(&filename, "Rust:\nsafe, fast, productive.\nTrust me.").unwrap();
+
+        let cfg = Config::new("rust", &[filename.to_str().unwrap()])
+            .with_case_insensitive(true)
+            .with_marker("[[", "]]");
+        let mut out = Vec::new();
+        run_with_writer(&cfg, &mut out).unwrap();
+        assert_eq!(
+            "[[Rust]]:\nT[[rust]] me.\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn query_file() {
+        let dir = temp_dir("query-file");
+        let query_file = dir.join("query.txt");
+        fs::write(&query_file, "fast, pro\n").unwrap();
+        let filename = dir.join("poem.txt");
+        fs::write(&filename, "Rust:\nsafe, fast, productive.\nPick three.").unwrap();
+
+        let args = vec![
+            "minigrep",
+            "--query-file",
+            query_file.to_str().unwrap(),
+            filename.to_str().unwrap(),
+        ];
+        let cfg = Config::from_args(args.into_iter().map(String::from)).unwrap();
+        assert_eq!("fast, pro", cfg.query());
+
+        let mut out = Vec::new();
+        run_with_writer(&cfg, &mut out).unwrap();
+        assert_eq!("safe, fast, productive.\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn field() {
+        let dir = temp_dir("field");
+        let filename = dir.join("log.tsv");
+        fs::write(
+            &filename,
+            "INFO\tserver started\t0ms\nERROR\tdisk full\t5ms\nERROR\n",
+        )
+        .unwrap();
+
+        let cfg = Config::new("ERROR", &[filename.to_str().unwrap()]).with_field(2, "\t");
+        let mut out = Vec::new();
+        run_with_writer(&cfg, &mut out).unwrap();
+        assert_eq!("disk full\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn absolute_paths() {
+        let dir = temp_dir("absolute-paths");
+        fs::write(dir.join("a.txt"), "Rust:").unwrap();
+        fs::write(dir.join("b.txt"), "Trust me.").unwrap();
+
+        // refer to the directory by a path which isn't canonical
+        let path = dir.join("..").join(dir.file_name().unwrap()).join(".");
+
+        let cfg = Config::new("rust", &[path.to_str().unwrap()])
+            .with_case_insensitive(true)
+            .with_absolute_paths(true);
+        let mut out = Vec::new();
+        run_with_writer(&cfg, &mut out).unwrap();
+        let dir = fs::canonicalize(&dir).unwrap();
+        assert_eq!(
+            format!(
+                "{}:Rust:\n{}:Trust me.\n",
+                dir.join("a.txt").display(),
+                dir.join("b.txt").display()
+            ),
+            String::from_utf8(out).unwrap(),
+        );
+    }
+
+    #[test]
+    fn group_by_file() {
+        let dir = temp_dir("group-by-file");
+        fs::write(
+            dir.join("a.txt"),
+            "Rust:\nsafe, fast, productive.\nTrust me.",
+        )
+        .unwrap();
+        fs::write(dir.join("b.txt"), "Pick three.").unwrap();
+        fs::write(dir.join("c.txt"), "rusty").unwrap();
+
+        let cfg = Config::new("rust", &[dir.to_str().unwrap()])
+            .with_case_insensitive(true)
+            .with_group_by_file(true);
+        let mut out = Vec::new();
+        run_with_writer(&cfg, &mut out).unwrap();
+        assert_eq!(
+            format!(
+                "{}\n  Rust:\n  Trust me.\n\n{}\n  rusty\n",
+                dir.join("a.txt").display(),
+                dir.join("c.txt").display()
+            ),
+            String::from_utf8(out).unwrap(),
+        );
+    }
+
+    #[test]
+    fn sibling_files() {
+        let dir = temp_dir("sibling-files");
+        let primary = dir.join("en.txt");
+        let sibling = dir.join("nl.txt");
+        fs::write(&primary, "Rust:\nsafe, fast, productive.\nTrust me.").unwrap();
+        fs::write(&sibling, "Roest:\nveilig, snel, productief.").unwrap();
+
+        let cfg = Config::new("rust", &[primary.to_str().unwrap()])
+            .with_case_insensitive(true)
+            .with_sibling_files(&[sibling.to_str().unwrap()]);
+        let mut out = Vec::new();
+        run_with_writer(&cfg, &mut out).unwrap();
+        // the sibling has no third line to pair with
+        assert_eq!(
+            format!("Rust:\n  {}:Roest:\nTrust me.\n", sibling.display()),
+            String::from_utf8(out).unwrap(),
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let dir = temp_dir("stats");
+        fs::write(dir.join("a.txt"), "Rust:\nsafe, fast, productive.\n").unwrap();
+        fs::write(dir.join("b.txt"), "Trust me.").unwrap();
+        let size = ["a.txt", "b.txt"]
+            .iter()
+            .map(|f| fs::metadata(dir.join(f)).unwrap().len())
+            .sum::<u64>();
+
+        for streaming in [false, true] {
+            let cfg = Config::new("rust", &[dir.to_str().unwrap()])
+                .with_case_insensitive(true)
+                .with_streaming(streaming)
+                .with_stats(true);
+            let mut err = Vec::new();
+            run_with_writers(&cfg, &mut Vec::new(), &mut err).unwrap();
+            let err = String::from_utf8(err).unwrap();
+            assert!(
+                err.starts_with(&format!("searched {} bytes in ", size)),
+                "{}",
+                err
+            );
+            assert!(err.ends_with(" MB/s)\n"));
+        }
+    }
+
+    #[test]
+    fn group_by_file_streaming() {
+        let dir = temp_dir("group-by-file-streaming");
+        let filename = dir.join("app.log");
+        fs::write(&filename, "rust 1\nskip\nrust 2\nrust 3\n").unwrap();
+
+        let cfg = Config::new("rust", &[dir.to_str().unwrap()])
+            .with_group_by_file(true)
+            .with_streaming(true);
+        let mut out = Vec::new();
+        run_with_writer(&cfg, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(1, out.matches(&filename.display().to_string()).count());
+        assert_eq!(
+            format!("{}\n  rust 1\n  rust 2\n  rust 3\n", filename.display()),
+            out
+        );
+    }
+
+    #[test]
+    fn csv() {
+        let dir = temp_dir("csv");
+        let filename = dir.join("quotes.txt");
+        fs::write(
+            &filename,
+            "Rust:\nHe said \"rust\", then left.\nPick three.\nrust",
+        )
+        .unwrap();
+
+        let path = filename.to_str().unwrap();
+        let cfg = Config::new("rust", &[path]).with_format(OutputFormat::Csv);
+        let mut out = Vec::new();
+        run_with_writer(&cfg, &mut out).unwrap();
+        assert_eq!(
+            format!(
+                "{},2,\"He said \"\"rust\"\", then left.\"\n{},4,rust\n",
+                path, path
+            ),
+            String::from_utf8(out).unwrap(),
+        );
+    }
+
+    #[test]
+    fn positions() {
+        let dir = temp_dir("positions");
+        let filename = dir.join("data.txt");
+        fs::write(&filename, "Rust:\nno match\nrüst or rust, RUST!\n").unwrap();
+        let path = filename.to_str().unwrap();
+
+        for (column_unit, columns) in [
+            (ColumnUnit::Bytes, [1, 10, 16]),
+            (ColumnUnit::Chars, [1, 9, 15]),
+        ] {
+            let cfg = Config::new("rust", &[path])
+                .with_case_insensitive(true)
+                .with_format(OutputFormat::Positions)
+                .with_column_unit(column_unit);
+            let mut out = Vec::new();
+            run_with_writer(&cfg, &mut out).unwrap();
+            assert_eq!(
+                format!(
+                    "{path}:1:{}:Rust:\n{path}:3:{}:rüst or rust, RUST!\n{path}:3:{}:rüst or rust, RUST!\n",
+                    columns[0], columns[1], columns[2],
+                ),
+                String::from_utf8(out).unwrap(),
+                "{:?}",
+                column_unit
+            );
+        }
+    }
+
+    #[test]
+    fn hidden() {
+        let dir = temp_dir("hidden");
+        fs::write(dir.join(".env"), "RUST_LOG=debug").unwrap();
+        fs::create_dir(dir.join(".git")).unwrap();
+        fs::write(dir.join(".git").join("config"), "[rust]").unwrap();
+        fs::write(dir.join("main.rs"), "// rust").unwrap();
+
+        let cfg = Config::new("rust", &[dir.to_str().unwrap()]).with_case_insensitive(true);
+        let mut out = Vec::new();
+        run_with_writer(&cfg, &mut out).unwrap();
+        assert_eq!(
+            format!("{}:// rust\n", dir.join("main.rs").display()),
+            String::from_utf8(out).unwrap(),
+        );
+
+        let cfg = cfg.with_hidden(true);
+        let mut out = Vec::new();
+        run_with_writer(&cfg, &mut out).unwrap();
+        assert_eq!(
+            format!(
+                "{}:RUST_LOG=debug\n{}:[rust]\n{}:// rust\n",
+                dir.join(".env").display(),
+                dir.join(".git").join("config").display(),
+                dir.join("main.rs").display()
+            ),
+            String::from_utf8(out).unwrap(),
+        );
+    }
+
+    #[test]
+    fn unique() {
+        let dir = temp_dir("unique");
+        fs::write(dir.join("a.txt"), "Rust:\nTrust me.\nRust:\nPick three.").unwrap();
+        fs::write(dir.join("b.txt"), "Trust me.\nrust").unwrap();
+
+        let cfg = Config::new("rust", &[dir.to_str().unwrap()])
+            .with_case_insensitive(true)
+            .with_unique(true);
+        let mut out = Vec::new();
+        run_with_writer(&cfg, &mut out).unwrap();
+        assert_eq!(
+            format!(
+                "{}:Rust:\n{}:Trust me.\n{}:rust\n",
+                dir.join("a.txt").display(),
+                dir.join("a.txt").display(),
+                dir.join("b.txt").display()
+            ),
+            String::from_utf8(out).unwrap(),
+        );
+    }
+
+    #[test]
+    fn skip_unreadable_files() {
+        let dir = temp_dir("skip-unreadable");
+        fs::write(dir.join("a.txt"), "Rust:").unwrap();
+        // not valid UTF-8
+        fs::write(dir.join("b.bin"), [0x72, 0x75, 0x73, 0x74, 0xff]).unwrap();
+        fs::write(dir.join("c.txt"), "Trust me.").unwrap();
+
+        let cfg = Config::new("rust", &[dir.to_str().unwrap()]).with_case_insensitive(true);
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+        run_with_writers(&cfg, &mut out, &mut err).unwrap();
+        assert_eq!(
+            format!(
+                "{}:Rust:\n{}:Trust me.\n",
+                dir.join("a.txt").display(),
+                dir.join("c.txt").display()
+            ),
+            String::from_utf8(out).unwrap(),
+        );
+        let err = String::from_utf8(err).unwrap();
+        assert!(err.starts_with("skipped 1 unreadable file(s):\n"));
+        assert!(err.contains(&dir.join("b.bin").display().to_string()));
+
+        let cfg = cfg.with_fail_fast(true);
+        assert!(matches!(
+            run_with_writers(&cfg, &mut Vec::new(), &mut Vec::new()),
+            Err(Error::IO(_))
+        ));
+
+        // a file given explicitly is never skipped
+        let missing = dir.join("missing.txt");
+        let cfg = Config::new("rust", &[missing.to_str().unwrap()]);
+        assert!(matches!(
+            run_with_writers(&cfg, &mut Vec::new(), &mut Vec::new()),
+            Err(Error::IO(_))
+        ));
+    }
+
+    #[test]
+    fn context_chars() {
+        let dir = temp_dir("context-chars");
+        let filename = dir.join("min.js");
+        let line = format!(
+            "{}rust{}Rust{}rust",
+            "a".repeat(100),
+            "b".repeat(4),
+            "c".repeat(100)
+        );
+        fs::write(&filename, &line).unwrap();
+
+        let cfg = Config::new("rust", &[filename.to_str().unwrap()])
+            .with_case_insensitive(true)
+            .with_context_chars(3);
+        let mut out = Vec::new();
+        run_with_writer(&cfg, &mut out).unwrap();
+        assert_eq!(
+            "...aaarustbbbbRustccc...cccrust\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn byte_count() {
+        let dir = temp_dir("byte-count");
+        let filename = dir.join("data.txt");
+        fs::write(&filename, "Rust rust\nno match\nrüst RUST!\n").unwrap();
+
+        for (byte_count, expected) in [(ByteCount::Matches, "12\n"), (ByteCount::Lines, "20\n")] {
+            let cfg = Config::new("rust", &[filename.to_str().unwrap()])
+                .with_case_insensitive(true)
+                .with_byte_count(byte_count);
+            let mut out = Vec::new();
+            run_with_writer(&cfg, &mut out).unwrap();
+            assert_eq!(
+                expected,
+                String::from_utf8(out).unwrap(),
+                "{:?}",
+                byte_count
+            );
+        }
+    }
+
+    #[test]
+    fn histogram() {
+        let dir = temp_dir("histogram");
+        let filename = dir.join("data.txt");
+        let contents = "\
+x
+x2
+x23456789
+skip me
+x234567890123456789012
+x2345678901234567890123
+";
+        fs::write(&filename, contents).unwrap();
+
+        let cfg = Config::new("x", &[filename.to_str().unwrap()]).with_histogram(10);
+        let mut out = Vec::new();
+        run_with_writer(&cfg, &mut out).unwrap();
+        assert_eq!(
+            " 0-9  | ### 3\n10-19 |  0\n20-29 | ## 2\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn longest() {
+        let dir = temp_dir("longest");
+        fs::write(
+            dir.join("a.txt"),
+            "rust\nrust is longer\nno match at all, even longer\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("b.txt"),
+            "rust is tied\nrust is the longest\na tie, rust longest\n",
+        )
+        .unwrap();
+
+        let cfg = Config::new("rust", &[dir.to_str().unwrap()])
+            .with_longest(true)
+            .with_marker("[", "]");
+        let mut out = Vec::new();
+        run_with_writer(&cfg, &mut out).unwrap();
+        assert_eq!(
+            format!("{}:2:[rust] is the longest\n", dir.join("b.txt").display()),
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn streaming() {
+        let dir = temp_dir("streaming");
+        let filename = dir.join("large.log");
+        // a match early on in a large file, which turns out to be invalid UTF-8 at its end
+        let mut contents = b"rust\n".to_vec();
+        contents.extend(b"nothing to see here\n".repeat(100_000));
+        contents.extend([0x72, 0x75, 0x73, 0x74, 0xff]);
+        fs::write(&filename, &contents).unwrap();
+
+        let cfg = Config::new("rust", &[filename.to_str().unwrap()]);
+        let mut out = Vec::new();
+        let result = run_with_writers(&cfg, &mut out, &mut Vec::new());
+        assert!(matches!(result, Err(Error::IO(_))));
+        assert_eq!("", String::from_utf8(out).unwrap());
+
+        let cfg = cfg.with_streaming(true);
+        let mut out = Vec::new();
+        let result = run_with_writers(&cfg, &mut out, &mut Vec::new());
+        assert!(matches!(result, Err(Error::IO(_))));
+        assert_eq!("rust\n", String::from_utf8(out).unwrap());
+
+        // found within a directory, it is skipped instead
+        let cfg = Config::new("rust", &[dir.to_str().unwrap()]).with_streaming(true);
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+        run_with_writers(&cfg, &mut out, &mut err).unwrap();
+        assert_eq!(
+            format!("{}:rust\n", filename.display()),
+            String::from_utf8(out).unwrap()
+        );
+        assert!(String::from_utf8(err)
+            .unwrap()
+            .starts_with("skipped 1 unreadable file(s):\n"));
+
+        let filename = dir.join("small.log");
+        fs::write(&filename, "nothing to see here\n").unwrap();
+        let cfg = Config::new("rust", &[filename.to_str().unwrap()]).with_streaming(true);
+        let result = run_with_writers(&cfg, &mut Vec::new(), &mut Vec::new());
+        assert!(matches!(result, Err(Error::NoResults)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn streaming_prints_before_the_input_ends() {
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::Duration;
+
+        let dir = temp_dir("streaming-early");
+        let fifo = dir.join("input");
+        let status = std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let (tx, rx) = mpsc::channel();
+        let path = fifo.clone();
+        let search = thread::spawn(move || {
+            let cfg = Config::new("rust", &[path.to_str().unwrap()]).with_streaming(true);
+            run_with_writers(&cfg, &mut ChannelWriter(tx), &mut Vec::new())
+        });
+
+        let mut input = fs::OpenOptions::new().write(true).open(&fifo).unwrap();
+        input.write_all(b"nothing to see here\nrust\n").unwrap();
+        // the match is printed while the input is still open
+        let mut printed = Vec::new();
+        while !printed.ends_with(b"\n") {
+            printed.extend(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+        }
+        assert_eq!("rust\n", String::from_utf8(printed).unwrap());
+
+        input.write_all(b"the end\n").unwrap();
+        drop(input);
+        search.join().unwrap().unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+
+    // Writer sending everything written to it over a channel.
+    #[cfg(unix)]
+    struct ChannelWriter(std::sync::mpsc::Sender<Vec<u8>>);
+
+    #[cfg(unix)]
+    impl Write for ChannelWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0
+                .send(buf.to_vec())
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn byte_range() {
+        let dir = temp_dir("byte-range");
+        let filename = dir.join("data.txt");
+        // lines start at the offsets 0, 7, 14, 21 and 28
+        fs::write(&filename, "rust 1\nrust 2\nrust 3\nrust 4\nrust 5\n").unwrap();
+
+        for ((start, end), expected) in [
+            ((0, 35), "rust 1\nrust 2\nrust 3\nrust 4\nrust 5\n"),
+            ((6, 14), "rust 2\n"),
+            ((7, 14), "rust 2\n"),
+            ((8, 15), "rust 3\n"),
+            ((22, 100), "rust 5\n"),
+        ] {
+            let cfg =
+                Config::new("rust", &[filename.to_str().unwrap()]).with_byte_range(start, end);
+            let mut out = Vec::new();
+            run_with_writer(&cfg, &mut out).unwrap();
+            assert_eq!(
+                expected,
+                String::from_utf8(out).unwrap(),
+                "{}..{}",
+                start,
+                end
+            );
+        }
+
+        let cfg = Config::new("rust", &[filename.to_str().unwrap()]).with_byte_range(8, 14);
+        assert!(matches!(
+            run_with_writer(&cfg, &mut Vec::new()),
+            Err(Error::NoResults)
+        ));
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(0, exit_code(&Ok(()), false));
+        assert_eq!(1, exit_code(&Err(Error::NoResults), false));
+        assert_eq!(2, exit_code(&Err(Error::MissingArg("filename")), false));
+        assert_eq!(
+            2,
+            exit_code(&Err(Error::IO(String::from("file not found"))), false)
+        );
+
+        let cfg = Config::new("rust", &["does-not-exist.txt"]);
+        assert_eq!(2, exit_code(&run_with_writer(&cfg, &mut Vec::new()), false));
+
+        // no match among the files which could be read, also without failing fast
+        let dir = temp_dir("exit-codes");
+        fs::write(dir.join("a.txt"), "safe, fast, productive.").unwrap();
+        fs::write(dir.join("b.bin"), [0x72, 0x75, 0x73, 0x74, 0xff]).unwrap();
+        let cfg = Config::new("rust", &[dir.to_str().unwrap()]);
+        let result = run_with_writers(&cfg, &mut Vec::new(), &mut Vec::new());
+        assert_eq!(2, exit_code(&result, false));
+    }
+
+    #[test]
+    fn inverted_exit_codes() {
+        let dir = temp_dir("invert-exit");
+        let filename = dir.join("poem.txt");
+        fs::write(&filename, "Rust:\nsafe, fast, productive.").unwrap();
+
+        for (query, expected) in [("Rust", 1), ("Go", 0)] {
+            let cfg = Config::new(query, &[filename.to_str().unwrap()]).with_invert_exit(true);
+            let result = run_with_writer(&cfg, &mut Vec::new());
+            assert_eq!(expected, exit_code(&result, cfg.invert_exit()), "{}", query);
+        }
+        assert_eq!(2, exit_code(&Err(Error::MissingArg("filename")), true));
+
+        // a mistyped path doesn't pass as the query being absent
+        let missing = dir.join("peom.txt");
+        let cfg = Config::new("Go", &[missing.to_str().unwrap()]).with_invert_exit(true);
+        let result = run_with_writer(&cfg, &mut Vec::new());
+        assert_eq!(2, exit_code(&result, cfg.invert_exit()));
+    }
 }
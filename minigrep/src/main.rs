@@ -1,7 +1,17 @@
-use minigrep::{run, Config, Error};
+use minigrep::{exit_code, run, Config, Error};
 use std::env;
+use std::process;
 
-fn main() -> Result<(), Error> {
-    let cfg = Config::from_args(env::args())?;
-    run(cfg)
+fn main() {
+    let mut invert_exit = false;
+    let result = Config::from_args(env::args()).and_then(|cfg| {
+        invert_exit = cfg.invert_exit();
+        run(cfg)
+    });
+    match &result {
+        // like grep, not finding anything is reported by the exit code only
+        Ok(()) | Err(Error::NoResults) => (),
+        Err(e) => eprintln!("minigrep: {}", e),
+    }
+    process::exit(exit_code(&result, invert_exit));
 }
@@ -0,0 +1,56 @@
+use std::fs;
+use std::path::Path;
+
+use crate::error::Error;
+
+/// An entry found while walking.
+pub enum Entry<'a> {
+    /// A file, which is to be searched.
+    File(&'a Path),
+    /// A directory which could not be walked, as it could not be read.
+    Unreadable(&'a Path, Error),
+}
+
+/// Visit the file at the given path, or if it is a directory,
+/// all files found within it recursively, in alphabetical order.
+/// Hidden files and directories found within it, those starting with a `.`,
+/// are only visited when `hidden` is true.
+///
+/// The visitor returns `false` in order to stop the walk early,
+/// in which case `false` is returned by the walk as well.
+pub fn walk<F>(path: &Path, hidden: bool, visit: &mut F) -> Result<bool, Error>
+where
+    F: FnMut(Entry) -> Result<bool, Error>,
+{
+    if !path.is_dir() {
+        return visit(Entry::File(path));
+    }
+
+    let entries = fs::read_dir(path).and_then(|entries| {
+        entries
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()
+    });
+    let mut entries = match entries {
+        Ok(entries) => entries,
+        Err(e) => return visit(Entry::Unreadable(path, Error::from(e))),
+    };
+    entries.sort();
+
+    for entry in entries {
+        if !hidden && is_hidden(&entry) {
+            continue;
+        }
+        if !walk(&entry, hidden, visit)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with('.'))
+        .unwrap_or(false)
+}
@@ -0,0 +1,43 @@
+//! Compares serializing a response using its `Display` implementation
+//! against writing it into a reused buffer, as done when handling a connection.
+//!
+//! Run it using `cargo bench -p webservice --bench response`.
+
+use std::time::{Duration, Instant};
+
+use webservice::HTTPResponse;
+
+const ITERATIONS: u32 = 100_000;
+
+fn main() {
+    let content = "Hello, World! ".repeat(64);
+    let response = HTTPResponse::new(200)
+        .with_content_type("text/plain; charset=utf-8")
+        .with_content(&content);
+
+    let mut size = 0;
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        size += response.to_string().len();
+    }
+    report("Display", start.elapsed(), size);
+
+    let mut size = 0;
+    let mut buffer = Vec::new();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        buffer.clear();
+        response.write_to(&mut buffer).unwrap();
+        size += buffer.len();
+    }
+    report("write_to (reused buffer)", start.elapsed(), size);
+}
+
+fn report(name: &str, elapsed: Duration, size: usize) {
+    println!(
+        "{}: {:?} per response ({} bytes written in total)",
+        name,
+        elapsed / ITERATIONS,
+        size
+    );
+}
@@ -1,4 +1,3 @@
-use std::fs;
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -85,27 +84,19 @@ fn main() {
 
     // add all handlers
 
-    server.add_handle(
-        HTTPMethod::Get,
-        "/",
-        Box::new(|| {
-            let contents = fs::read_to_string("hello.html")?;
-            Ok(HTTPResponse::new(200).with_content(&contents))
-        }),
-    );
+    server.serve_dir("/", "static");
     server.add_handle(
         HTTPMethod::Get,
         "/sleep",
-        Box::new(|| {
+        Box::new(|_| {
             thread::sleep(Duration::from_secs(5));
-            let contents = fs::read_to_string("hello.html")?;
-            Ok(HTTPResponse::new(200).with_content(&contents))
+            Ok(HTTPResponse::from_parts(200, Some("Good morning!")))
         }),
     );
     server.add_handle(
         HTTPMethod::Get,
         "/forbidden",
-        Box::new(|| Ok(HTTPResponse::new(403))),
+        Box::new(|_| Ok(HTTPResponse::from_parts(403, None))),
     );
 
     // add signal handling
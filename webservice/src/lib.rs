@@ -1,6 +1,6 @@
 //! A very minimal HTTP Server allowing you to server
 //! header-less content over GET/POST methods,
-//! without the ability to inspect received headers or use of query parameters.
+//! with the ability to inspect received headers, bodies and query parameters.
 //!
 //! Really a useless HTTP server, and served only to allow the author
 //! to get some experience in writing a small multi-threaded library with stored closures.
@@ -12,7 +12,7 @@
 //!
 //! let mut server: HTTPServer = Default::default();
 //!
-//! server.add_handle(HTTPMethod::Get, "/", Box::new(|| {
+//! server.add_handle(HTTPMethod::Get, "/", Box::new(|_| {
 //!     Ok(HTTPResponse::new(200).with_content(r#"<!DOCTYPE html>
 //! <html lang="en">
 //! <head>
@@ -31,23 +31,41 @@
 //! // server.listen(0).unwrap();
 //! ```
 
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt;
+use std::fs;
 use std::io;
 use std::io::prelude::*;
-use std::net::TcpListener;
+use std::iter::FromIterator;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::mpsc;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use socket2::SockRef;
 
 pub mod thread;
 
 use self::thread::ThreadPool;
 
 /// Typed definitions of the HTTP methods supported by this server.
+///
+/// `HEAD` requests for which no handle is added are handled by
+/// the handle added for `GET`, leaving out the body of its response.
+/// Requests for a path which only has handles added for other methods
+/// are responded to with a `405`, listing those methods in its `Allow` header.
 pub enum HTTPMethod {
     Get,
     Post,
+    Put,
+    Delete,
+    Patch,
+    Head,
+    Options,
 }
 
 /// Unrestricted HTTP Status codes, as the author is too lazy
@@ -59,18 +77,58 @@ impl fmt::Display for HTTPMethod {
         f.write_str(match self {
             HTTPMethod::Get => "GET",
             HTTPMethod::Post => "POST",
+            HTTPMethod::Put => "PUT",
+            HTTPMethod::Delete => "DELETE",
+            HTTPMethod::Patch => "PATCH",
+            HTTPMethod::Head => "HEAD",
+            HTTPMethod::Options => "OPTIONS",
         })
     }
 }
 
+/// Return the standard reason phrase of a common [HTTPStatus](self::HTTPStatus),
+/// e.g. `Not Found` for `404`, or an empty string for any other status.
+pub fn reason_phrase(status: HTTPStatus) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        417 => "Expectation Failed",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
 /// Response returned by an [HTTPHandle](self::HTTPHandle),
 /// defining the status and optionally also content.
 ///
-/// Only UTF-8 content is supported for simplicity sake.
-/// For the same reason headers aren't supported either.
+/// Content is UTF-8 text, or raw bytes for anything else (e.g. images).
+/// The `Content-Length` header is computed from that content,
+/// unless it was set explicitly as one of its headers.
+#[derive(Clone)]
 pub struct HTTPResponse {
     status: HTTPStatus,
-    content: Option<String>,
+    content: Option<Vec<u8>>,
+    body_stream: Option<Arc<Mutex<BodyStream>>>,
+    content_type: Option<String>,
+    vary: Vec<String>,
+    headers: Vec<(String, String)>,
+    trailers: Vec<(String, String)>,
+    // streamed body ending where the connection is closed, instead of being chunked
+    close_delimited: bool,
 }
 
 impl HTTPResponse {
@@ -82,393 +140,6013 @@ impl HTTPResponse {
         HTTPResponse {
             status,
             content: None,
+            body_stream: None,
+            content_type: None,
+            vary: Vec::new(),
+            headers: Vec::new(),
+            trailers: Vec::new(),
+            close_delimited: false,
+        }
+    }
+
+    /// Create a new [HTTPResponse](self::HTTPResponse) for
+    /// a given [HTTPStatus](self::HTTPStatus) and optional (UTF-8) content,
+    /// a shorthand for [new](self::HTTPResponse::new) optionally followed by
+    /// [with_content](self::HTTPResponse::with_content).
+    pub fn from_parts(status: HTTPStatus, content: Option<&str>) -> HTTPResponse {
+        let response = HTTPResponse::new(status);
+        match content {
+            Some(content) => response.with_content(content),
+            None => response,
         }
     }
 
+    /// Create a new [HTTPResponse](self::HTTPResponse) for
+    /// a given [HTTPStatus](self::HTTPStatus) with the given JSON as content,
+    /// setting its `Content-Type` to `application/json`.
+    ///
+    /// The JSON is sent as it is, without validating it.
+    pub fn json(status: HTTPStatus, body: &str) -> HTTPResponse {
+        HTTPResponse::new(status)
+            .with_content(body)
+            .with_content_type("application/json")
+    }
+
+    /// Create a new `200` [HTTPResponse](self::HTTPResponse) with the content
+    /// of the variant which best matches the given `Accept-Language` header value,
+    /// or the default content if no variant is acceptable.
+    /// As the content depends on the request, `Accept-Language` is added to the `Vary` header.
+    ///
+    /// Languages are tried in order of their quality value, where a language
+    /// is matched by its full tag first (e.g. `en-US`), and its primary tag second (e.g. `en`).
+    pub fn by_language(
+        accept_language: &str,
+        variants: &HashMap<String, String>,
+        default: &str,
+    ) -> HTTPResponse {
+        let find = |tag: &str| {
+            variants
+                .iter()
+                .find(|(language, _)| language.eq_ignore_ascii_case(tag))
+                .map(|(_, content)| content.as_str())
+        };
+        let content = accepted_values(accept_language)
+            .iter()
+            .find_map(|tag| {
+                let primary = tag.split('-').next().unwrap_or(tag);
+                find(tag).or_else(|| find(primary))
+            })
+            .unwrap_or(default);
+        HTTPResponse::new(200)
+            .with_content(content)
+            .with_vary("Accept-Language")
+    }
+
+    /// Create a new `200` [HTTPResponse](self::HTTPResponse) with the content type and content
+    /// of the variant which best matches the given `Accept` header value,
+    /// or the default content type and content if no variant is acceptable.
+    /// As the content depends on the request, `Accept` is added to the `Vary` header.
+    ///
+    /// Media ranges are tried in order of their quality value, where a range matches a type
+    /// exactly (e.g. `text/html`), by its type (e.g. `text/*`) or as any type (`*/*`),
+    /// the latter two matching the first such variant.
+    pub fn by_type(accept: &str, variants: &[(&str, &str)], default: (&str, &str)) -> HTTPResponse {
+        let (content_type, content) = accepted_values(accept)
+            .iter()
+            .find_map(|range| {
+                variants.iter().copied().find(|(content_type, _)| {
+                    let essence = content_type.split(';').next().unwrap_or_default().trim();
+                    match range.strip_suffix("/*") {
+                        Some("*") => true,
+                        Some(prefix) => essence
+                            .split_once('/')
+                            .is_some_and(|(t, _)| t.eq_ignore_ascii_case(prefix)),
+                        None => essence.eq_ignore_ascii_case(range),
+                    }
+                })
+            })
+            .unwrap_or(default);
+        HTTPResponse::new(200)
+            .with_content(content)
+            .with_content_type(content_type)
+            .with_vary("Accept")
+    }
+
     /// Consume this [HTTPResponse](self::HTTPResponse) and return
     /// a new response with (UTF-8) content added to it.
     pub fn with_content(self, content: &str) -> HTTPResponse {
         HTTPResponse {
-            content: Some(String::from(content)),
+            content: Some(content.as_bytes().to_vec()),
+            body_stream: None,
             ..self
         }
     }
-}
 
-impl fmt::Display for HTTPResponse {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let content = match &self.content {
-            Some(content) => format!(
-                "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n{}",
-                self.status,
-                content.len(),
-                content,
-            ),
-            None => format!("HTTP/1.1 {}\r\n\r\n", self.status),
-        };
-        f.write_str(&content)
+    /// Consume this [HTTPResponse](self::HTTPResponse) and return
+    /// a new response with content of raw bytes added to it.
+    ///
+    /// Such content is only displayed lossily, as UTF-8, by the [Display](std::fmt::Display)
+    /// implementation, use [write_to](self::HTTPResponse::write_to) to write it as it is.
+    pub fn with_bytes(self, content: Vec<u8>) -> HTTPResponse {
+        HTTPResponse {
+            content: Some(content),
+            body_stream: None,
+            ..self
+        }
     }
-}
 
-/// Definition of an HTTP Handle that can be added to an [HTTPServer](self::HTTPServer)
-/// in order to serve content for a static path for a specific method.
-pub type HTTPHandle = Box<dyn Fn() -> io::Result<HTTPResponse> + Sync + Send>;
-
-// Executor used to handle a connection.
-pub type HandleExecutor = Box<dyn FnMut(HandleFn)>;
-
-// Function given to a handle executor to handle a connection.
-pub type HandleFn = Box<dyn FnOnce() + Send>;
-
-/// Minimal HTTP Server, that can be used
-/// to handle the most simple HTTP calls.
-pub struct HTTPServer {
-    handles: HashMap<String, HTTPHandle>,
-    shutdown: Option<mpsc::Receiver<()>>,
-    executor: Option<HandleExecutor>,
-}
-
-impl Default for HTTPServer {
-    fn default() -> Self {
-        Self::new()
+    /// Consume this [HTTPResponse](self::HTTPResponse) and return
+    /// a new response with a body produced chunk by chunk by the given [BodyStream](self::BodyStream),
+    /// instead of content of which the length is known upfront.
+    ///
+    /// Such a body is sent using the chunked transfer encoding, without a `Content-Length`,
+    /// as each chunk is produced, such that the body is never held in memory as a whole.
+    /// HTTP/1.0 clients, which don't support that encoding, are sent the chunks as they are instead,
+    /// with the end of the body marked by closing the connection.
+    /// As a body can only be streamed once, clones of the response share its stream.
+    pub fn with_stream(self, stream: BodyStream) -> HTTPResponse {
+        HTTPResponse {
+            content: None,
+            body_stream: Some(Arc::new(Mutex::new(stream))),
+            ..self
+        }
     }
-}
 
-impl HTTPServer {
-    /// Create a new HTTP Server.
-    pub fn new() -> HTTPServer {
-        HTTPServer {
-            handles: HashMap::new(),
-            shutdown: None,
-            executor: None,
+    /// Consume this [HTTPResponse](self::HTTPResponse) and return
+    /// a new response with the given `Content-Type` header set.
+    pub fn with_content_type(self, content_type: &str) -> HTTPResponse {
+        HTTPResponse {
+            content_type: Some(String::from(content_type)),
+            ..self
         }
     }
 
-    /// Add an HTTP Handle for a specific method and path,
-    /// such that when the user makes a request to it,
-    /// the given handle can provide the response status code
-    /// and optionally also content.
+    /// Consume this [HTTPResponse](self::HTTPResponse) and return
+    /// a new response with the given request header field added to its `Vary` header,
+    /// such that caches know the response was negotiated based on it.
     ///
-    /// Note:
-    /// - No headers can be given;
-    /// - Path won't be matched if query parameters were given by the user;
-    /// - Existing handle with same path and method will be overwritten in silence.
-    pub fn add_handle(&mut self, method: HTTPMethod, path: &str, handle: HTTPHandle) {
-        let pattern = create_pattern(method, path);
-        self.handles.insert(pattern, handle);
+    /// A field already part of the `Vary` header isn't added again.
+    pub fn with_vary(mut self, field: &str) -> HTTPResponse {
+        if !self.vary.iter().any(|f| f.eq_ignore_ascii_case(field)) {
+            self.vary.push(String::from(field));
+        }
+        self
     }
 
-    /// Add a receiver that is to be send an empty value,
-    /// in order to trigger a graceful shutdown.
-    pub fn set_shutdown(&mut self, r: mpsc::Receiver<()>) {
-        self.shutdown = Some(r);
+    /// Consume this [HTTPResponse](self::HTTPResponse) and return
+    /// a new response with the given header added to it, after the headers
+    /// already added, such that a header can be given multiple times (e.g. `Set-Cookie`).
+    pub fn with_header(mut self, name: &str, value: &str) -> HTTPResponse {
+        self.headers.push((String::from(name), String::from(value)));
+        self
     }
 
-    /// Set a custom (pool) executor that will be called to
-    /// handle a connection. Allowing you to implement a custom
-    /// thread pool instead of the default [ThreadPool][self::thread::ThreadPool],
-    /// or to even do so in a concurrent fashion.
-    pub fn set_handle_executor(&mut self, f: HandleExecutor) {
-        self.executor = Some(f);
+    /// Consume this [HTTPResponse](self::HTTPResponse) and return
+    /// a new response with the given trailer added to it, a header sent after
+    /// the last chunk of a [streamed](self::HTTPResponse::with_stream) body,
+    /// of which the name is announced upfront in the `Trailer` header.
+    ///
+    /// Trailers of a response which isn't streamed are left out.
+    pub fn with_trailer(mut self, name: &str, value: &str) -> HTTPResponse {
+        self.trailers
+            .push((String::from(name), String::from(value)));
+        self
     }
 
-    /// Listen on the given local TCP port for incoming requests,
-    /// consuming this [HTTPServer](self::HTTPServer) and serving content
-    /// using the added [handlers](self::HTTPHandle).
-    pub fn listen(mut self, port: u16) -> io::Result<()> {
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
-        listener.set_nonblocking(true)?;
+    /// Set a header of this [HTTPResponse](self::HTTPResponse) in place,
+    /// replacing the value of a header with the same (case-insensitive) name.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        match self
+            .headers
+            .iter_mut()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        {
+            Some((_, v)) => *v = String::from(value),
+            None => self.headers.push((String::from(name), String::from(value))),
+        }
+    }
 
-        log::info!("HTTP Server listening at: {}", listener.local_addr()?);
+    /// Write this [HTTPResponse](self::HTTPResponse) as it is to be sent,
+    /// directly into the given writer, producing the same bytes as its
+    /// [Display](std::fmt::Display) implementation without allocating a `String` for it.
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        self.write_head(w)?;
+        match (&self.body_stream, &self.content) {
+            (Some(body), _) => self.write_body_stream(w, body),
+            (None, Some(content)) => w.write_all(content),
+            (None, None) => Ok(()),
+        }
+    }
 
-        let mut execute = match self.executor {
-            Some(e) => e,
-            None => {
-                let pool = ThreadPool::new(4).unwrap();
-                Box::new(move |f| {
-                    pool.execute(f);
-                })
+    // Write the status line and the headers,
+    // up to and including the empty line separating them from the body.
+    fn write_head(&self, w: &mut impl Write) -> io::Result<()> {
+        write!(
+            w,
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        )?;
+        if let Some(content_type) = &self.content_type {
+            write!(w, "Content-Type: {}\r\n", content_type)?;
+        }
+        if !self.vary.is_empty() {
+            write!(w, "Vary: {}\r\n", self.vary.join(", "))?;
+        }
+        for (name, value) in &self.headers {
+            if self.body_stream.is_some() && name.eq_ignore_ascii_case("Content-Length") {
+                log::warn!("Content-Length of streamed response set explicitly, leaving it out");
+                continue;
+            }
+            write!(w, "{}: {}\r\n", name, value)?;
+        }
+        if self.body_stream.is_some() && !self.close_delimited {
+            w.write_all(b"Transfer-Encoding: chunked\r\n")?;
+            if !self.trailers.is_empty() {
+                let names: Vec<&str> = self.trailers.iter().map(|(n, _)| n.as_str()).collect();
+                write!(w, "Trailer: {}\r\n", names.join(", "))?;
+            }
+        } else if let Some(content) = &self.content {
+            if self
+                .headers
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+            {
+                log::warn!(
+                    "Content-Length of response set explicitly, instead of using the content length {}",
+                    content.len()
+                );
+            } else {
+                write!(w, "Content-Length: {}\r\n", content.len())?;
             }
-        };
-
-        let handles = Arc::new(self.handles);
-
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let handles = Arc::clone(&handles);
-                    execute(Box::new(move || {
-                        if let Err(e) = handle_connection(handles, stream) {
-                            log::error!("failed to handle connection: {}", e);
-                        }
-                    }));
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    if let Some(ref shutdown) = self.shutdown {
-                        match shutdown.try_recv() {
-                            Err(e) => {
-                                if e == mpsc::TryRecvError::Empty {
-                                    continue;
-                                }
-                                log::error!("graceful shutdown channel was set, but has an unexpected error: {}", e);
-                                self.shutdown = None;
-                            }
-                            Ok(_) => {
-                                log::info!(
-                                    "Graceful shutdown signal received, stopping server now..."
-                                );
-                                break;
-                            }
-                        }
-                    };
-                }
-                Err(e) => {
-                    eprintln!("failed to handle connection: encountered IO error: {}", e);
-                }
-            };
         }
+        if (self.body_stream.is_none() || self.close_delimited) && !self.trailers.is_empty() {
+            log::warn!("trailers of a response which isn't chunked, leaving them out");
+        }
+        w.write_all(b"\r\n")
+    }
 
-        log::debug!("HTTP Server stopped listening!");
+    // Write a streamed body, chunked unless it is delimited by closing the connection.
+    fn write_body_stream(&self, w: &mut impl Write, body: &Mutex<BodyStream>) -> io::Result<()> {
+        if !self.close_delimited {
+            return write_chunks(w, body, &self.trailers);
+        }
+        for chunk in &mut *body.lock().unwrap() {
+            w.write_all(&chunk?)?;
+        }
         Ok(())
     }
+
+    fn has_body(&self) -> bool {
+        self.content.is_some() || self.body_stream.is_some()
+    }
 }
 
-fn create_pattern(method: HTTPMethod, path: &str) -> String {
-    if path.is_empty() {
-        return create_pattern(method, "/");
+impl fmt::Display for HTTPResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut buffer = Vec::new();
+        self.write_to(&mut buffer).map_err(|_| fmt::Error)?;
+        f.write_str(&String::from_utf8_lossy(&buffer))
     }
-    format!("{} {} HTTP/1.1\r\n", method, path)
 }
 
-fn handle_connection(
-    handles: Arc<HashMap<String, HTTPHandle>>,
-    mut stream: impl Read + Write,
+/// Body of an [HTTPResponse](self::HTTPResponse) produced chunk by chunk,
+/// as given to [with_stream](self::HTTPResponse::with_stream).
+pub type BodyStream = Box<dyn Iterator<Item = io::Result<Vec<u8>>> + Send>;
+
+// Write the chunks of a streamed body using the chunked transfer encoding,
+// ending it with the empty chunk followed by the trailers,
+// or stopping at the first error of the stream.
+fn write_chunks(
+    w: &mut impl Write,
+    body: &Mutex<BodyStream>,
+    trailers: &[(String, String)],
 ) -> io::Result<()> {
-    let mut buffer = [0; 1024];
-    for _ in 0..16 {
-        // retry a max amount of times
-        match stream.read(&mut buffer) {
-            Ok(_) => break,
-            Err(e) => match e.kind() {
-                io::ErrorKind::WouldBlock => {
-                    std::thread::sleep(Duration::from_millis(50));
-                    continue;
-                }
-                _ => return Err(e),
-            },
+    let mut body = body.lock().unwrap();
+    for chunk in &mut *body {
+        let chunk = chunk?;
+        if chunk.is_empty() {
+            // an empty chunk would mark the end of the body
+            continue;
         }
+        write!(w, "{:X}\r\n", chunk.len())?;
+        w.write_all(&chunk)?;
+        w.write_all(b"\r\n")?;
     }
-    if buffer[0] == 0 {
-        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    w.write_all(b"0\r\n")?;
+    for (name, value) in trailers {
+        write!(w, "{}: {}\r\n", name, value)?;
     }
+    w.write_all(b"\r\n")
+}
 
-    let mut response = None;
-
-    for (pattern, handle) in handles.iter() {
-        if buffer.starts_with(pattern.as_bytes()) {
-            log::debug!(
-                "TCP Request matched: {:?}",
-                String::from_utf8_lossy(&buffer).trim_end_matches('\u{0}')
-            );
-            response = Some(handle()?)
-        }
-    }
+/// Request as given to the handle of the request.
+#[derive(Debug, Clone, Default)]
+pub struct Request {
+    headers: RequestHeaders,
+    body: Vec<u8>,
+    query: QueryParams,
+    params: PathParams,
+    remote_addr: Option<SocketAddr>,
+}
 
-    log::debug!(
-        "404 response for TCP Request: {:?}",
-        String::from_utf8_lossy(&buffer).trim_end_matches('\u{0}')
-    );
+/// Query parameters of a [Request](self::Request), URL-decoded, by their name.
+///
+/// Only the last value is kept for a parameter which is given multiple times.
+pub type QueryParams = HashMap<String, String>;
 
-    let content = format!(
-        "{}",
-        match response {
-            Some(resp) => resp,
-            None => HTTPResponse::new(404).with_content(HTTP_CONTENT_404),
-        }
-    );
-    stream.write_all(content.as_bytes())?;
-    stream.flush()
-}
+/// Path parameters of a [Request](self::Request), URL-decoded, by their name,
+/// as captured by the `:name` segments of the path of its handle,
+/// and the trailing `*` segment capturing the remainder of the path as the `*` parameter.
+pub type PathParams = HashMap<String, String>;
 
-const HTTP_CONTENT_404: &str = r#"<!DOCTYPE html>
-<html lang="en">
-  <head>
-    <meta charset="utf-8">
-    <title>Hello!</title>
-  </head>
-  <body>
-    <h1>Oops!</h1>
-    <p>Sorry, I don't know what you're asking for.</p>
-  </body>
-</html>
-"#;
+impl Request {
+    /// Return the headers of this request.
+    pub fn headers(&self) -> &RequestHeaders {
+        &self.headers
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Return the value of the header with the given (case-insensitive) name, if given.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
 
-    #[test]
-    fn test_create_pattern() {
-        assert_eq!(
-            String::from("GET / HTTP/1.1\r\n"),
-            create_pattern(HTTPMethod::Get, ""),
-        );
-        assert_eq!(
-            String::from("GET / HTTP/1.1\r\n"),
-            create_pattern(HTTPMethod::Get, "/"),
-        );
-        assert_eq!(
-            String::from("POST / HTTP/1.1\r\n"),
-            create_pattern(HTTPMethod::Post, "/"),
-        );
-        assert_eq!(
-            String::from("POST /foo/bar HTTP/1.1\r\n"),
-            create_pattern(HTTPMethod::Post, "/foo/bar"),
-        );
-        // simple, not even path validation
-        assert_eq!(
-            String::from("POST 123_invalid@path-yeah HTTP/1.1\r\n"),
-            create_pattern(HTTPMethod::Post, "123_invalid@path-yeah"),
-        );
+    /// Return the body of this request, as many bytes as its `Content-Length`,
+    /// or empty if it has none.
+    pub fn body(&self) -> &[u8] {
+        &self.body
     }
 
-    #[test]
-    fn test_http_response_to_string_no_content() {
-        assert_eq!(
-            String::from("HTTP/1.1 403\r\n\r\n"),
-            format!("{}", HTTPResponse::new(403)),
-        );
+    /// Return the query parameters of this request, empty if it has none.
+    pub fn query(&self) -> &QueryParams {
+        &self.query
     }
 
-    #[test]
-    fn test_http_response_to_string_with_content() {
-        assert_eq!(
-            String::from("HTTP/1.1 200\r\nContent-Length: 13\r\n\r\nHello, World!"),
-            format!("{}", HTTPResponse::new(200).with_content("Hello, World!")),
-        );
+    /// Return the value of the path parameter with the given name,
+    /// captured by the `:name` segment of the path of the handle, if any.
+    /// The remainder of the path captured by a trailing `*` segment is named `*`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|value| value.as_str())
     }
 
-    #[derive(Debug, Default)]
-    struct ReadWriteMock {
-        data_to_read: String,
-        written_data: String,
-        written_data_flushed: String,
+    /// Return the path parameters of this request, empty if its handle has none.
+    pub fn params(&self) -> &PathParams {
+        &self.params
     }
 
-    impl ReadWriteMock {
-        fn clear(&mut self) {
-            self.data_to_read.clear();
-            self.written_data.clear();
-            self.written_data_flushed.clear();
-        }
+    /// Return the address of the client which made this request,
+    /// if its connection has one, which isn't the case for e.g. a Unix socket.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
     }
+}
 
-    impl io::Read for ReadWriteMock {
-        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-            if self.data_to_read.is_empty() {
-                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
-            }
-            let len = usize::min(buf.len(), self.data_to_read.len());
-            let slice = self.data_to_read.as_bytes();
-            buf[..len].copy_from_slice(&slice[..len]);
-            self.data_to_read = String::from(match std::str::from_utf8(&slice[len..]) {
-                Ok(v) => v,
-                Err(_) => {
-                    return Err(io::Error::from(io::ErrorKind::InvalidInput));
-                }
-            });
-            Ok(len)
-        }
+/// Headers of a [Request](self::Request).
+///
+/// Header names are case-insensitive, and the values of a header
+/// which is given multiple times are joined, separated by a comma.
+#[derive(Debug, Clone, Default)]
+pub struct RequestHeaders {
+    // header values by their lowercased name
+    headers: HashMap<String, String>,
+}
+
+impl RequestHeaders {
+    /// Return the value of the header with the given (case-insensitive) name, if given.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(|value| value.as_str())
     }
 
-    impl io::Write for ReadWriteMock {
-        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-            self.written_data += match std::str::from_utf8(buf) {
-                Ok(v) => v,
-                Err(_) => {
-                    return Err(io::Error::from(io::ErrorKind::InvalidInput));
-                }
+    // Parse the header lines of a (partial) request head,
+    // skipping the request line and leaving out malformed lines.
+    fn parse(head: &[u8]) -> RequestHeaders {
+        let mut headers: HashMap<String, String> = HashMap::new();
+        let head = String::from_utf8_lossy(head);
+        let lines = head
+            .split("\r\n")
+            .skip(1)
+            .take_while(|line| !line.is_empty());
+        for line in lines {
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) if !name.trim().is_empty() => (name.trim(), value.trim()),
+                _ => continue,
             };
-            Ok(buf.len())
+            headers
+                .entry(name.to_ascii_lowercase())
+                .and_modify(|values| {
+                    values.push_str(", ");
+                    values.push_str(value);
+                })
+                .or_insert_with(|| String::from(value));
         }
+        RequestHeaders { headers }
+    }
+}
 
-        fn flush(&mut self) -> io::Result<()> {
-            self.written_data_flushed += self.written_data.as_str();
-            self.written_data.clear();
-            Ok(())
+/// Writer given to a [StreamingHTTPHandle](self::StreamingHTTPHandle),
+/// allowing it to write its response in pieces, directly to the connection.
+///
+/// The status is to be written first, followed by the headers and finally the body.
+/// A `200` status is assumed if a header or body chunk is written without a status,
+/// and as the length of the body isn't known upfront,
+/// the body is sent using the chunked transfer encoding.
+/// The connection is closed once responded to, as announced
+/// by a `Connection: close` header written along with the status.
+pub struct ResponseWriter<'a> {
+    stream: &'a mut dyn Write,
+    status_written: bool,
+    body_started: bool,
+}
+
+impl<'a> ResponseWriter<'a> {
+    fn new(stream: &'a mut dyn Write) -> ResponseWriter<'a> {
+        ResponseWriter {
+            stream,
+            status_written: false,
+            body_started: false,
         }
     }
 
+    /// Write the status of the response,
+    /// which can only be done once and prior to any header or body chunk.
+    pub fn write_status(&mut self, status: HTTPStatus) -> io::Result<()> {
+        if self.status_written {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "response status already written",
+            ));
+        }
+        self.status_written = true;
+        write!(
+            self.stream,
+            "HTTP/1.1 {} {}\r\nConnection: close\r\n",
+            status,
+            reason_phrase(status)
+        )
+    }
+
+    /// Write a header of the response,
+    /// which can only be done prior to any body chunk.
+    pub fn write_header(&mut self, key: &str, value: &str) -> io::Result<()> {
+        if self.body_started {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "response header written after body",
+            ));
+        }
+        self.ensure_status()?;
+        write!(self.stream, "{}: {}\r\n", key, value)
+    }
+
+    /// Write a chunk of the response body, empty chunks are ignored.
+    pub fn write_body_chunk(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.ensure_status()?;
+        if !self.body_started {
+            self.body_started = true;
+            self.stream
+                .write_all(b"Transfer-Encoding: chunked\r\n\r\n")?;
+        }
+        if chunk.is_empty() {
+            // an empty chunk would mark the end of the body
+            return Ok(());
+        }
+        write!(self.stream, "{:X}\r\n", chunk.len())?;
+        self.stream.write_all(chunk)?;
+        self.stream.write_all(b"\r\n")
+    }
+
+    // Complete the response, whatever the handle wrote of it.
+    fn finish(mut self) -> io::Result<()> {
+        self.ensure_status()?;
+        if self.body_started {
+            self.stream.write_all(b"0\r\n\r\n")
+        } else {
+            self.stream.write_all(b"\r\n")
+        }
+    }
+
+    fn ensure_status(&mut self) -> io::Result<()> {
+        if self.status_written {
+            return Ok(());
+        }
+        self.write_status(200)
+    }
+}
+
+// Parse the values of an Accept or Accept-Language header value, without their parameters,
+// ordered from most to least preferred, leaving out the unacceptable ones.
+fn accepted_values(accept: &str) -> Vec<&str> {
+    let mut values: Vec<(&str, f32)> = accept
+        .split(',')
+        .filter_map(|value| {
+            let mut parts = value.split(';');
+            let value = parts.next()?.trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse().ok())?;
+            if value.is_empty() || quality <= 0.0 {
+                return None;
+            }
+            Some((value, quality))
+        })
+        .collect();
+    // stable sort, preserving the order of values with equal quality
+    values.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    values.into_iter().map(|(value, _)| value).collect()
+}
+
+/// `RouteError` is the error returned when a route
+/// cannot be added to an [HTTPServer](self::HTTPServer).
+#[derive(Debug, PartialEq)]
+pub struct RouteError {
+    /// The kind of error that happened,
+    /// allowing you to handle the error appropriately.
+    pub kind: RouteErrorKind,
+    /// A human readable message for debugging purposes only.
+    pub message: &'static str,
+}
+
+/// Defines the kind of error that happened when adding a route
+/// to an [HTTPServer](self::HTTPServer).
+#[derive(Debug, PartialEq)]
+pub enum RouteErrorKind {
+    /// Indicates that the path of the route is not one
+    /// a request can be made for, and thus would never be matched.
+    InvalidPath,
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RouteError::{:?}: {}", self.kind, self.message)
+    }
+}
+
+/// Definition of an HTTP Handle that can be added to an [HTTPServer](self::HTTPServer)
+/// in order to serve content for a static path for a specific method.
+///
+/// # Example
+///
+/// ```
+/// use webservice::{HTTPHandle, HTTPResponse};
+///
+/// let handle: HTTPHandle = Box::new(|request| {
+///     let name = request.query().get("name").map_or("stranger", |name| name.as_str());
+///     Ok(HTTPResponse::new(200).with_content(&format!("Hello, {}!", name)))
+/// });
+/// ```
+pub type HTTPHandle = Box<dyn Fn(&Request) -> io::Result<HTTPResponse> + Sync + Send>;
+
+/// Transformation applied by an [HTTPServer](self::HTTPServer) to every response
+/// returned by its handles, as well as the ones it responds with itself (e.g. a `404`).
+pub type ResponseTransformer = Box<dyn Fn(&mut HTTPResponse) + Send + Sync>;
+
+/// Logic run by an [HTTPServer](self::HTTPServer) for every request once it is responded to,
+/// given information about the request and its response, e.g. to write an access log.
+pub type Middleware = Box<dyn Fn(&RequestInfo, &HTTPResponse) + Send + Sync>;
+
+/// Information about a request given to a [Middleware](self::Middleware).
+#[derive(Debug, Clone)]
+pub struct RequestInfo {
+    /// Method of the request, e.g. `GET`.
+    pub method: String,
+    /// Path of the request, without its query.
+    pub path: String,
+    /// Address of the client which made the request, if its connection has one.
+    pub remote_addr: Option<SocketAddr>,
+    /// Time it took to respond to the request, from the moment it was received.
+    pub duration: Duration,
+}
+
+/// Definition of an HTTP Handle which writes its response in pieces
+/// using a [ResponseWriter](self::ResponseWriter), rather than returning it as a whole.
+pub type StreamingHTTPHandle =
+    Box<dyn Fn(&Request, &mut ResponseWriter) -> io::Result<()> + Sync + Send>;
+
+/// Definition of an HTTP Handle which reads the body of its request as it is received,
+/// rather than being given a request of which the body was read as a whole.
+/// The body can be read up to the length declared by its `Content-Length`.
+pub type UploadHTTPHandle =
+    Box<dyn Fn(&Request, &mut dyn Read) -> io::Result<HTTPResponse> + Sync + Send>;
+
+// Executor used to handle a connection.
+pub type HandleExecutor = Box<dyn FnMut(HandleFn)>;
+
+// Function given to a handle executor to handle a connection.
+pub type HandleFn = Box<dyn FnOnce() + Send>;
+
+/// Source of the current time of an [HTTPServer](self::HTTPServer),
+/// such that time-dependent features can be tested by advancing a fake clock,
+/// instead of having to wait on the time to pass.
+pub trait Clock: Send + Sync {
+    /// Return the current moment.
+    fn now(&self) -> Instant;
+}
+
+/// [Clock](self::Clock) returning the actual time, used by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Handle used to pause and resume the accepting of new connections
+/// by a listening [HTTPServer](self::HTTPServer).
+///
+/// Connections already accepted are still served while paused,
+/// new ones are left waiting on the listener until resumed.
+#[derive(Debug, Clone, Default)]
+pub struct PauseHandle {
+    paused: Arc<AtomicBool>,
+}
+
+impl PauseHandle {
+    /// Stop accepting new connections.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Start accepting new connections again.
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns true if accepting new connections is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+}
+
+/// Table of the [HTTPHandles](self::HTTPHandle) of an [HTTPServer](self::HTTPServer),
+/// of which the routes can be replaced as a whole while listening,
+/// e.g. to reload them on a configuration change without restarting.
+///
+/// A request is handled using the table as it was when the request was received,
+/// such that requests in flight see either the old or the new routes, never a mix of both.
+/// [StreamingHTTPHandles](self::StreamingHTTPHandle) are not part of the table.
+#[derive(Clone, Default)]
+pub struct RouteTable {
+    handles: Arc<RwLock<Arc<Routes<HTTPHandle>>>>,
+}
+
+impl RouteTable {
+    /// Replace all routes of the table at once, each defined by its method and path,
+    /// the same as if they were added using [add_routes](self::HTTPServer::add_routes)
+    /// to a server without any.
+    ///
+    /// # Errors
+    ///
+    /// A [RouteError](self::RouteError) is returned with kind [RouteErrorKind::InvalidPath](self::RouteErrorKind::InvalidPath)
+    /// if the path of any of the routes is invalid, as for [try_add_handle](self::HTTPServer::try_add_handle),
+    /// in which case the routes of the table are left as they were.
+    pub fn replace_routes(
+        &self,
+        routes: Vec<(HTTPMethod, &str, HTTPHandle)>,
+    ) -> Result<(), RouteError> {
+        for (_, path, _) in &routes {
+            validate_path(path)?;
+        }
+        let handles = routes
+            .into_iter()
+            .map(|(method, path, handle)| (create_pattern(method, path), handle))
+            .collect();
+        *self.handles.write().unwrap() = Arc::new(handles);
+        Ok(())
+    }
+
+    // Return the routes as they are right now.
+    fn snapshot(&self) -> Arc<Routes<HTTPHandle>> {
+        Arc::clone(&self.handles.read().unwrap())
+    }
+
+    // Modify the routes in place, which is only possible while no request is being handled.
+    fn modify<R>(&self, f: impl FnOnce(&mut Routes<HTTPHandle>) -> R) -> R {
+        let mut handles = self.handles.write().unwrap();
+        f(Arc::get_mut(&mut handles).expect("routes are modified while requests are handled"))
+    }
+}
+
+// Handles of a single kind, by the method and path they were added for, with those
+// of paths with parameters kept apart per method, such that a request without an exact match
+// only has to be matched against the paths with parameters of its own method.
+// Routes match requests of both HTTP/1.0 and HTTP/1.1, as they only differ in their defaults.
+struct Routes<H> {
+    handles: HashMap<String, H>,
+    parameterized: HashMap<String, Vec<String>>,
+}
+
+impl<H> Default for Routes<H> {
+    fn default() -> Self {
+        Routes {
+            handles: HashMap::new(),
+            parameterized: HashMap::new(),
+        }
+    }
+}
+
+impl<H> FromIterator<(String, H)> for Routes<H> {
+    fn from_iter<I: IntoIterator<Item = (String, H)>>(iter: I) -> Self {
+        let mut routes = Routes::default();
+        for (pattern, handle) in iter {
+            routes.insert(&pattern, handle);
+        }
+        routes
+    }
+}
+
+impl<H> Routes<H> {
+    fn insert(&mut self, pattern: &str, handle: H) -> Option<H> {
+        // a pattern without a valid path is kept, yet never matched
+        let key = route_key(pattern).unwrap_or(pattern);
+        if is_parameterized(key) && !self.handles.contains_key(key) {
+            if let Some((method, _)) = key.split_once(' ') {
+                self.parameterized
+                    .entry(method.to_owned())
+                    .or_default()
+                    .push(key.to_owned());
+            }
+        }
+        self.handles.insert(key.to_owned(), handle)
+    }
+
+    fn remove(&mut self, pattern: &str) -> Option<H> {
+        let key = route_key(pattern).unwrap_or(pattern);
+        let handle = self.handles.remove(key)?;
+        if let Some((method, _)) = key.split_once(' ') {
+            if let Some(keys) = self.parameterized.get_mut(method) {
+                keys.retain(|k| k != key);
+            }
+        }
+        Some(handle)
+    }
+
+    // Find the handle matching the method and path of the request line exactly, or otherwise
+    // the one with a path with parameters matching it, returning its route and the parameters.
+    // Of multiple matching paths with parameters, one without a trailing `*` is preferred,
+    // followed by the one with the fewest parameters.
+    fn find(&self, request_line: &str) -> Option<(&str, &H, PathParams)> {
+        let key = route_key(request_line)?;
+        if let Some((route, handle)) = self.handles.get_key_value(key) {
+            return Some((route, handle, PathParams::new()));
+        }
+        let (method, path) = key.split_once(' ')?;
+        self.parameterized
+            .get(method)?
+            .iter()
+            .filter_map(|route| {
+                let (_, route_path) = route.split_once(' ')?;
+                let params = match_path(route_path, path)?;
+                Some((route.as_str(), &self.handles[route], params))
+            })
+            .min_by_key(|(route, _, params)| (params.contains_key("*"), params.len(), *route))
+    }
+
+    // Add the methods of all routes matching the given path to the given ones.
+    fn add_methods<'a>(&'a self, path: &str, methods: &mut Vec<&'a str>) {
+        for route in self.handles.keys() {
+            let (method, route_path) = match route.split_once(' ') {
+                Some(parts) => parts,
+                None => continue,
+            };
+            if route_path == path
+                || (is_parameterized(route) && match_path(route_path, path).is_some())
+            {
+                methods.push(method);
+            }
+        }
+    }
+}
+
+// Return the route of a request line (or pattern), being its method and path,
+// or none if it isn't an HTTP/1.0 or HTTP/1.1 request line.
+fn route_key(line: &str) -> Option<&str> {
+    let (method, path, version) = split_request_line(line)?;
+    match version {
+        "HTTP/1.0\r\n" | "HTTP/1.1\r\n" => Some(&line[..method.len() + 1 + path.len()]),
+        _ => None,
+    }
+}
+
+// Whether the path of a route has parameters, as opposed to being matched exactly.
+fn is_parameterized(route: &str) -> bool {
+    route.contains("/:") || route.ends_with("/*")
+}
+
+// Everything a listening server shares with the handling of its connections.
+#[derive(Default)]
+struct ServerContext {
+    handles: RouteTable,
+    streaming_handles: Routes<StreamingHTTPHandle>,
+    upload_handles: Routes<UploadHTTPHandle>,
+    not_found_handle: Option<HTTPHandle>,
+    default_content_type: Option<String>,
+    max_header_line_bytes: Option<usize>,
+    max_send_rate: Option<u64>,
+    response_transformers: Vec<ResponseTransformer>,
+    middlewares: Vec<Middleware>,
+    global_headers: Vec<(String, String)>,
+    empty_200_as_204: bool,
+    in_flight: Option<Mutex<HashMap<String, Arc<Flight>>>>,
+    latencies: Option<LatencyTracker>,
+    max_request_line_bytes: Option<usize>,
+    max_head_bytes: Option<usize>,
+    max_body_size: Option<usize>,
+    stopping: AtomicBool,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl ServerContext {
+    // Return the current moment according to the clock of the server.
+    fn now(&self) -> Instant {
+        match &self.clock {
+            Some(clock) => clock.now(),
+            None => Instant::now(),
+        }
+    }
+}
+
+/// Information about a connection which is being handled
+/// by an [HTTPServer](self::HTTPServer).
+#[derive(Debug, Clone)]
+pub struct ConnInfo {
+    /// Address of the client which made the connection.
+    pub remote_addr: SocketAddr,
+    /// Moment at which the server started handling the connection.
+    pub started: Instant,
+}
+
+impl ConnInfo {
+    /// Time elapsed since the server started handling the connection.
+    pub fn age(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// Handle used to inspect the connections being handled
+/// by a listening [HTTPServer](self::HTTPServer).
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionTracker {
+    connections: Arc<Mutex<HashMap<u64, ConnInfo>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl ConnectionTracker {
+    /// List the connections currently being handled, oldest first.
+    pub fn active_connections(&self) -> Vec<ConnInfo> {
+        let mut connections: Vec<ConnInfo> =
+            self.connections.lock().unwrap().values().cloned().collect();
+        connections.sort_by_key(|info| info.started);
+        connections
+    }
+
+    // Track a connection for as long as the returned guard lives.
+    fn track(&self, remote_addr: SocketAddr) -> TrackedConnection {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let info = ConnInfo {
+            remote_addr,
+            started: Instant::now(),
+        };
+        self.connections.lock().unwrap().insert(id, info);
+        TrackedConnection {
+            tracker: self.clone(),
+            id,
+        }
+    }
+}
+
+struct TrackedConnection {
+    tracker: ConnectionTracker,
+    id: u64,
+}
+
+impl Drop for TrackedConnection {
+    fn drop(&mut self) {
+        self.tracker.connections.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Summary of the durations it took the handle of a route
+/// to produce its response, as recorded by a [LatencyTracker](self::LatencyTracker).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LatencySummary {
+    /// Amount of responses recorded for the route in total.
+    pub count: u64,
+    /// Median duration.
+    pub p50: Duration,
+    /// Duration within which 90% of the responses were produced.
+    pub p90: Duration,
+    /// Duration within which 99% of the responses were produced.
+    pub p99: Duration,
+}
+
+/// Handle used to inspect the latencies of the routes of
+/// an [HTTPServer](self::HTTPServer) with latency tracking enabled.
+///
+/// Only the most recent durations of a route are kept, bounding the memory used per route,
+/// such that its percentiles are based on those durations only.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyTracker {
+    routes: Arc<Mutex<HashMap<String, RouteLatencies>>>,
+}
+
+#[derive(Debug, Default)]
+struct RouteLatencies {
+    count: u64,
+    // ring buffer of the most recent durations
+    samples: Vec<Duration>,
+}
+
+const LATENCY_SAMPLES_PER_ROUTE: usize = 1024;
+
+impl LatencyTracker {
+    /// Summarize the latencies of each route which received requests, by route
+    /// (e.g. `GET /`).
+    pub fn route_latencies(&self) -> HashMap<String, LatencySummary> {
+        let routes = self.routes.lock().unwrap();
+        routes
+            .iter()
+            .map(|(route, latencies)| {
+                let mut samples = latencies.samples.clone();
+                samples.sort();
+                // nearest-rank percentile
+                let percentile = |p: usize| samples[(samples.len() * p).div_ceil(100) - 1];
+                let summary = LatencySummary {
+                    count: latencies.count,
+                    p50: percentile(50),
+                    p90: percentile(90),
+                    p99: percentile(99),
+                };
+                (route.clone(), summary)
+            })
+            .collect()
+    }
+
+    fn record(&self, route: &str, duration: Duration) {
+        let mut routes = self.routes.lock().unwrap();
+        let latencies = match routes.get_mut(route) {
+            Some(latencies) => latencies,
+            None => routes.entry(String::from(route)).or_default(),
+        };
+        let index = (latencies.count % LATENCY_SAMPLES_PER_ROUTE as u64) as usize;
+        if index < latencies.samples.len() {
+            latencies.samples[index] = duration;
+        } else {
+            latencies.samples.push(duration);
+        }
+        latencies.count += 1;
+    }
+}
+
+// Socket options applied to each accepted connection,
+// leaving those which are not set to the OS defaults.
+#[derive(Default)]
+struct StreamOptions {
+    linger: Option<Option<Duration>>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    read_timeout: Option<Duration>,
+}
+
+impl StreamOptions {
+    fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+        if let Some(linger) = self.linger {
+            socket.set_linger(linger)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(timeout) = self.read_timeout {
+            stream.set_read_timeout(Some(timeout))?;
+        }
+        Ok(())
+    }
+}
+
+/// Minimal HTTP Server, that can be used
+/// to handle the most simple HTTP calls.
+pub struct HTTPServer {
+    ctx: ServerContext,
+    shutdown: Option<mpsc::Receiver<()>>,
+    executor: Option<HandleExecutor>,
+    pool_size: usize,
+    pause: PauseHandle,
+    max_pending: Option<usize>,
+    retry_after: Option<Duration>,
+    accept_interval: Option<Duration>,
+    accept_poll_interval: Duration,
+    stream_options: StreamOptions,
+    keep_alive_timeout: Duration,
+    shutdown_timeout: Option<Duration>,
+    connections: ConnectionTracker,
+}
+
+impl Default for HTTPServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HTTPServer {
+    /// Create a new HTTP Server.
+    pub fn new() -> HTTPServer {
+        HTTPServer {
+            ctx: Default::default(),
+            shutdown: None,
+            executor: None,
+            pool_size: DEFAULT_POOL_SIZE,
+            pause: Default::default(),
+            max_pending: None,
+            retry_after: None,
+            accept_interval: None,
+            accept_poll_interval: DEFAULT_ACCEPT_POLL_INTERVAL,
+            stream_options: Default::default(),
+            keep_alive_timeout: DEFAULT_KEEP_ALIVE_TIMEOUT,
+            shutdown_timeout: None,
+            connections: Default::default(),
+        }
+    }
+
+    /// Add an HTTP Handle for a specific method and path,
+    /// such that when the user makes a request to it,
+    /// the given handle can provide the response status code
+    /// and optionally also content.
+    ///
+    /// A segment of the path starting with a `:` matches any non-empty segment,
+    /// available to the handle as the path parameter of that name, e.g. `/users/:id`
+    /// matches `/users/42` with `id` being `42`. A trailing `*` segment matches the remainder
+    /// of the path, available as the `*` path parameter, e.g. `/static/*` matches `/static/css/app.css`
+    /// with `*` being `css/app.css`. A path without such segments is matched exactly,
+    /// which takes precedence over paths with parameters, which in turn take precedence over
+    /// paths ending with a `*`.
+    ///
+    /// Note:
+    /// - The handle is given the request, including its headers, body, query and path parameters;
+    /// - Path is matched without the query parameters given by the user;
+    /// - HTTP/1.0 and HTTP/1.1 requests are matched alike;
+    /// - Existing handle with same path and method will be overwritten in silence.
+    pub fn add_handle(&mut self, method: HTTPMethod, path: &str, handle: HTTPHandle) {
+        let pattern = create_pattern(method, path);
+        self.ctx.streaming_handles.remove(&pattern);
+        self.ctx.upload_handles.remove(&pattern);
+        self.ctx
+            .handles
+            .modify(|handles| handles.insert(&pattern, handle));
+    }
+
+    /// Add an HTTP Handle for a specific method and path,
+    /// same as [add_handle](self::HTTPServer::add_handle), but only
+    /// if the path is one which can actually be matched by a request.
+    ///
+    /// # Errors
+    ///
+    /// A [RouteError](self::RouteError) is returned with kind [RouteErrorKind::InvalidPath](self::RouteErrorKind::InvalidPath)
+    /// if the path doesn't start with a `/`, or contains whitespace or control characters.
+    pub fn try_add_handle(
+        &mut self,
+        method: HTTPMethod,
+        path: &str,
+        handle: HTTPHandle,
+    ) -> Result<(), RouteError> {
+        validate_path(path)?;
+        self.add_handle(method, path, handle);
+        Ok(())
+    }
+
+    /// Add a [StreamingHTTPHandle](self::StreamingHTTPHandle) for a specific method and path,
+    /// which writes its response directly to the connection as it is produced.
+    ///
+    /// The same notes apply as for [add_handle](self::HTTPServer::add_handle),
+    /// with the addition that the default content type isn't applied to its responses.
+    pub fn add_streaming_handle(
+        &mut self,
+        method: HTTPMethod,
+        path: &str,
+        handle: StreamingHTTPHandle,
+    ) {
+        let pattern = create_pattern(method, path);
+        self.ctx.handles.modify(|handles| handles.remove(&pattern));
+        self.ctx.upload_handles.remove(&pattern);
+        self.ctx.streaming_handles.insert(&pattern, handle);
+    }
+
+    /// Add an [UploadHTTPHandle](self::UploadHTTPHandle) for a specific method and path,
+    /// which reads the body of its request as it is received, such that a large
+    /// body can be processed without holding it in memory as a whole.
+    ///
+    /// The same notes apply as for [add_handle](self::HTTPServer::add_handle), except that
+    /// the body isn't limited by the [max body size](self::HTTPServer::set_max_body_size).
+    /// The connection is closed once responded to, if the handle didn't read the body entirely.
+    pub fn add_upload_handle(&mut self, method: HTTPMethod, path: &str, handle: UploadHTTPHandle) {
+        let pattern = create_pattern(method, path);
+        self.ctx.handles.modify(|handles| handles.remove(&pattern));
+        self.ctx.streaming_handles.remove(&pattern);
+        self.ctx.upload_handles.insert(&pattern, handle);
+    }
+
+    /// Add multiple HTTP Handles at once, each defined by its method and path,
+    /// which is convenient in case all routes are defined in a single place.
+    ///
+    /// Each route is added as if [add_handle](self::HTTPServer::add_handle) was called for it,
+    /// meaning that a later route overwrites an earlier one with the same path and method.
+    pub fn add_routes(&mut self, routes: Vec<(HTTPMethod, &str, HTTPHandle)>) {
+        for (method, path, handle) in routes {
+            self.add_handle(method, path, handle);
+        }
+    }
+
+    /// Serve the files within the root directory for `GET` requests to paths under the mount path,
+    /// e.g. `/static/css/app.css` serves `root/css/app.css` when mounted at `/static`.
+    ///
+    /// The `Content-Type` of a file is guessed from its extension.
+    /// A path to a directory serves its `index.html` file, if it has one.
+    /// A path to a file which doesn't exist is responded to with the default `404` page,
+    /// and one trying to escape the root directory (e.g. using `..`) with a `403`.
+    pub fn serve_dir(&mut self, mount: &str, root: impl Into<PathBuf>) {
+        let root = root.into();
+        let path = format!("{}/*", mount.trim_end_matches('/'));
+        self.add_handle(
+            HTTPMethod::Get,
+            &path,
+            Box::new(move |request| {
+                let mut path = match resolve_file(&root, request.param("*").unwrap_or_default()) {
+                    Some(path) => path,
+                    None => return Ok(HTTPResponse::new(403)),
+                };
+                if path.is_dir() {
+                    path.push("index.html");
+                }
+                if !path.is_file() {
+                    return Ok(not_found());
+                }
+                let response = HTTPResponse::new(200).with_bytes(fs::read(&path)?);
+                Ok(match guess_content_type(&path) {
+                    Some(content_type) => response.with_content_type(content_type),
+                    None => response,
+                })
+            }),
+        );
+    }
+
+    /// Return the [RouteTable](self::RouteTable) of this server,
+    /// which can be used to replace its routes while listening.
+    pub fn route_table(&self) -> RouteTable {
+        self.ctx.handles.clone()
+    }
+
+    /// Set the `Content-Type` used for responses which have content,
+    /// but for which the handle didn't set a content type itself.
+    pub fn set_default_content_type(&mut self, content_type: &str) {
+        self.ctx.default_content_type = Some(String::from(content_type));
+    }
+
+    /// Respond using this handle to requests of which the path matches no route,
+    /// for any method, instead of the default `404` page. The handle can respond with any status,
+    /// such as a redirect to a search page.
+    pub fn set_not_found_handler(&mut self, handle: HTTPHandle) {
+        self.ctx.not_found_handle = Some(handle);
+    }
+
+    /// Respond with a `204` (No Content) instead, whenever a handle
+    /// returns a `200` response without content. Disabled by default.
+    pub fn set_empty_200_as_204(&mut self, enabled: bool) {
+        self.ctx.empty_200_as_204 = enabled;
+    }
+
+    /// Coalesce identical `GET` requests which are handled at the same time,
+    /// such that the handle is called only once for all of them, each getting the same response.
+    /// Requests are identical when their path and query are, no matter their headers.
+    /// Disabled by default.
+    ///
+    /// This is meant for expensive handles of which the response doesn't depend on the headers,
+    /// to not compute the same response multiple times when many requests for it arrive at once.
+    /// [StreamingHTTPHandles](self::StreamingHTTPHandle) are never coalesced, and as a
+    /// [streamed body](self::HTTPResponse::with_stream) can only be sent once,
+    /// requests waiting on a response with such a body are handled by the handle themselves.
+    pub fn set_coalesce_requests(&mut self, enabled: bool) {
+        self.ctx.in_flight = match enabled {
+            true => Some(Mutex::new(HashMap::new())),
+            false => None,
+        };
+    }
+
+    /// Add a [ResponseTransformer](self::ResponseTransformer), applied right before
+    /// a response is written, in the order in which transformers were added.
+    ///
+    /// This is convenient to add headers which are to be part of every response,
+    /// such as `X-Content-Type-Options: nosniff`. Responses written by a
+    /// [StreamingHTTPHandle](self::StreamingHTTPHandle) aren't transformed.
+    pub fn add_response_transformer(&mut self, transformer: ResponseTransformer) {
+        self.ctx.response_transformers.push(transformer);
+    }
+
+    /// Add a [Middleware](self::Middleware), run for every request once it is responded to,
+    /// including the responses of the server itself (e.g. a `404` or `500`),
+    /// in the order in which middlewares were added.
+    ///
+    /// A middleware is given the response before the
+    /// [response transformers](self::HTTPServer::add_response_transformer) are applied.
+    /// Requests handled by a [StreamingHTTPHandle](self::StreamingHTTPHandle),
+    /// or rejected before being handled (e.g. with a `431` or `400`), don't go through middlewares.
+    pub fn add_middleware(&mut self, middleware: Middleware) {
+        self.ctx.middlewares.push(middleware);
+    }
+
+    /// Add a header to every response, e.g. an `Alt-Svc` header, unless the response
+    /// already has a header with the same (case-insensitive) name, such that handles
+    /// can override it. Added headers come after the headers of the response itself.
+    ///
+    /// Global headers are added before [response transformers](self::ResponseTransformer) are applied,
+    /// and same as those, not to responses written by a [StreamingHTTPHandle](self::StreamingHTTPHandle).
+    pub fn add_global_header(&mut self, name: &str, value: &str) {
+        self.ctx
+            .global_headers
+            .push((String::from(name), String::from(value)));
+    }
+
+    /// Set the max amount of bytes the request line of a request can have,
+    /// excluding its line ending, 8 KiB by default. A request with a longer request line
+    /// is responded to with a `431` without being handled.
+    pub fn set_max_request_line_bytes(&mut self, n: usize) {
+        self.ctx.max_request_line_bytes = Some(n);
+    }
+
+    /// Set the max amount of bytes the head of a request can have, that is its request line
+    /// and header lines, 64 KiB by default. A request with a larger head
+    /// is responded to with a `431` without being handled.
+    pub fn set_max_head_bytes(&mut self, n: usize) {
+        self.ctx.max_head_bytes = Some(n);
+    }
+
+    /// Set the max amount of bytes the body of a request can have, 1 MiB by default.
+    /// A request declaring a longer `Content-Length` is responded to with a `413`
+    /// without reading its body or being handled, or with a `417` if it sent
+    /// an `Expect: 100-continue` header, as its client hasn't sent the body yet.
+    /// Any other request expecting to continue is told to do so before reading its body.
+    pub fn set_max_body_size(&mut self, n: usize) {
+        self.ctx.max_body_size = Some(n);
+    }
+
+    /// Set the max amount of bytes a single header line of a request can have,
+    /// excluding its line ending, such that a request with a longer header line
+    /// is responded to with a `431` without being handled, as soon as that line
+    /// turns out to be too long, without reading the rest of the head.
+    pub fn set_max_header_line_bytes(&mut self, n: usize) {
+        self.ctx.max_header_line_bytes = Some(n);
+    }
+
+    /// Limit the rate at which the body of a response is sent to about
+    /// the given amount of bytes per second, pausing in between parts of it,
+    /// which is useful to simulate a slow network when testing clients.
+    ///
+    /// The head of a response isn't limited, nor are streamed bodies
+    /// or the responses written by a [StreamingHTTPHandle](self::StreamingHTTPHandle).
+    ///
+    /// Panics if the rate is 0.
+    pub fn set_max_send_rate(&mut self, bytes_per_sec: u64) {
+        assert!(bytes_per_sec > 0, "max send rate must be positive");
+        self.ctx.max_send_rate = Some(bytes_per_sec);
+    }
+
+    /// Add a receiver that is to be send an empty value,
+    /// in order to trigger a graceful shutdown.
+    ///
+    /// Connections kept alive are closed once idle, or after responding
+    /// with a `Connection: close` header to the request being handled.
+    pub fn set_shutdown(&mut self, r: mpsc::Receiver<()>) {
+        self.shutdown = Some(r);
+    }
+
+    /// Set how long the server waits on a graceful shutdown for the connections
+    /// still being handled to finish, before it returns anyway.
+    /// The server waits for all of them without such a timeout.
+    ///
+    /// When the timeout elapses, a warning is logged and the server returns `Ok`,
+    /// [detaching](self::thread::ThreadPool::detach) its default [ThreadPool](self::thread::ThreadPool),
+    /// such that the remaining connections finish in the background.
+    /// An [executor](self::HTTPServer::set_handle_executor) set instead is dropped as usual,
+    /// which for a [pool executor](self::HTTPServer::pool_executor) means waiting on the pool,
+    /// unless it is detached or still shared with another owner.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = Some(timeout);
+    }
+
+    /// Get a [PauseHandle](self::PauseHandle) which can be used
+    /// to pause and resume accepting new connections once listening.
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.pause.clone()
+    }
+
+    /// Get a [ConnectionTracker](self::ConnectionTracker) which can be used
+    /// to inspect the connections being handled once listening.
+    pub fn connection_tracker(&self) -> ConnectionTracker {
+        self.connections.clone()
+    }
+
+    /// Record how long it takes the handles of this server to produce their response,
+    /// which can be inspected per route using the [LatencyTracker](self::LatencyTracker)
+    /// returned by [latency_tracker](self::HTTPServer::latency_tracker).
+    pub fn enable_latency_tracking(&mut self) {
+        if self.ctx.latencies.is_none() {
+            self.ctx.latencies = Some(LatencyTracker::default());
+        }
+    }
+
+    /// Get the [LatencyTracker](self::LatencyTracker) which can be used
+    /// to inspect the latencies of the routes once listening,
+    /// if latency tracking is enabled.
+    pub fn latency_tracker(&self) -> Option<LatencyTracker> {
+        self.ctx.latencies.clone()
+    }
+
+    /// Set a custom (pool) executor that will be called to
+    /// handle a connection. Allowing you to implement a custom
+    /// thread pool instead of the default [ThreadPool][self::thread::ThreadPool],
+    /// of which only the [size](self::HTTPServer::with_pool_size) can be configured,
+    /// or to even do so in a concurrent fashion.
+    pub fn set_handle_executor(&mut self, f: HandleExecutor) {
+        self.executor = Some(f);
+    }
+
+    /// Set the amount of threads of the [ThreadPool](self::thread::ThreadPool)
+    /// which handles the connections, unless another
+    /// [executor](self::HTTPServer::set_handle_executor) is set. 4 by default.
+    ///
+    /// The pool is only created once [running](self::RunningServer::run),
+    /// which fails with an [InvalidInput](std::io::ErrorKind::InvalidInput) error
+    /// if it can't be created with this size, e.g. 0.
+    pub fn with_pool_size(&mut self, size: usize) {
+        self.pool_size = size;
+    }
+
+    /// Set the max amount of accepted connections which are waiting
+    /// on the executor to be handled. Any connection accepted beyond this
+    /// amount is rejected immediately with a `503` response.
+    ///
+    /// This bounds the backlog handed to the executor,
+    /// not the amount of connections being handled concurrently.
+    pub fn set_max_pending(&mut self, n: usize) {
+        self.max_pending = Some(n);
+    }
+
+    /// Set the delay after which clients are asked to retry when their connection is rejected
+    /// for going over the [max pending](self::HTTPServer::set_max_pending) connections,
+    /// sent as the `Retry-After` header of the `503` response, in seconds rounded up.
+    pub fn set_retry_after(&mut self, delay: Duration) {
+        self.retry_after = Some(delay);
+    }
+
+    /// Set the [Clock](self::Clock) consulted by the server for the time-dependent features,
+    /// being the [accept rate](self::HTTPServer::set_accept_rate), the
+    /// [keep-alive](self::HTTPServer::set_keep_alive_timeout) and
+    /// [shutdown](self::HTTPServer::set_shutdown_timeout) timeouts and the
+    /// [latencies](self::HTTPServer::enable_latency_tracking) of the routes.
+    /// The [SystemClock](self::SystemClock) by default.
+    ///
+    /// Timeouts of the connections themselves, such as the [read timeout](self::HTTPServer::set_read_timeout),
+    /// are enforced by the OS, and as such always follow the actual time.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.ctx.clock = Some(clock);
+    }
+
+    /// Set the max amount of connections accepted per second, spread evenly over that second.
+    /// Connections beyond that rate are left waiting on the listener until their turn,
+    /// same as when [paused](self::PauseHandle::pause).
+    ///
+    /// Panics if the rate is 0.
+    pub fn set_accept_rate(&mut self, per_sec: u32) {
+        assert!(per_sec > 0, "accept rate must be positive");
+        self.accept_interval = Some(Duration::from_secs(1) / per_sec);
+    }
+
+    /// Set how long the server sleeps when no connection is waiting to be accepted,
+    /// before checking for one again, 10 milliseconds by default. A graceful shutdown
+    /// is noticed within this interval, while a shorter one costs more CPU when idle.
+    ///
+    /// Panics if the interval is zero.
+    pub fn set_accept_poll_interval(&mut self, interval: Duration) {
+        assert!(!interval.is_zero(), "accept poll interval must be positive");
+        self.accept_poll_interval = interval;
+    }
+
+    /// Set the linger option of accepted connections, defining if closing them
+    /// blocks for up to the given duration in order to send the remaining data,
+    /// or resets the connection immediately for a zero duration.
+    /// `None` disables lingering, which is what is used when not set.
+    pub fn set_linger(&mut self, linger: Option<Duration>) {
+        self.stream_options.linger = Some(linger);
+    }
+
+    /// Set the size of the receive buffer of accepted connections (`SO_RCVBUF`).
+    /// The OS might adjust the size, e.g. Linux doubles it for bookkeeping.
+    pub fn set_recv_buffer_size(&mut self, size: usize) {
+        self.stream_options.recv_buffer_size = Some(size);
+    }
+
+    /// Set the size of the send buffer of accepted connections (`SO_SNDBUF`).
+    /// The OS might adjust the size, e.g. Linux doubles it for bookkeeping.
+    pub fn set_send_buffer_size(&mut self, size: usize) {
+        self.stream_options.send_buffer_size = Some(size);
+    }
+
+    /// Set how long reading from accepted connections can block,
+    /// after which a request with an incomplete body is responded to with a `408`.
+    /// Reads never time out when not set.
+    ///
+    /// A timed out read of the request head is retried up to 16 times, 50 milliseconds apart,
+    /// such that a slow client has about 16 times this timeout to send its request head.
+    /// The connection is dropped with a logged warning if the head wasn't received by then.
+    ///
+    /// Panics if the timeout is zero.
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        assert!(!timeout.is_zero(), "read timeout must be positive");
+        self.stream_options.read_timeout = Some(timeout);
+    }
+
+    /// Set how long a connection kept alive is waited on for its next request,
+    /// after which it is closed. 5 seconds by default.
+    ///
+    /// An HTTP/1.1 connection is kept alive unless its client asks for it to be closed,
+    /// with a `Connection: close` header, while an HTTP/1.0 connection is only kept alive
+    /// when its client asks for it, with a `Connection: keep-alive` header.
+    ///
+    /// While waiting, a connection holds on to a thread of the executor. It is therefore
+    /// closed as soon as another connection is waiting on the executor to be handled.
+    ///
+    /// Panics if the timeout is zero.
+    pub fn set_keep_alive_timeout(&mut self, timeout: Duration) {
+        assert!(!timeout.is_zero(), "keep-alive timeout must be positive");
+        self.keep_alive_timeout = timeout;
+    }
+
+    /// Create a [HandleExecutor](self::HandleExecutor) which handles
+    /// each connection synchronously on the thread that accepted it.
+    ///
+    /// Meant for tests, as it makes the flow of a request through the server
+    /// fully deterministic: no threads and no timing are involved.
+    pub fn inline_executor() -> HandleExecutor {
+        Box::new(|f| f())
+    }
+
+    /// Create a [HandleExecutor](self::HandleExecutor) which handles
+    /// connections on the given [ThreadPool](self::thread::ThreadPool).
+    ///
+    /// As the pool is shared, the same pool can be used by multiple servers,
+    /// e.g. an admin server listening on another port than the main server.
+    pub fn pool_executor(pool: Arc<ThreadPool>) -> HandleExecutor {
+        Box::new(move |f| pool.execute(f))
+    }
+
+    /// Listen on the given local TCP port for incoming requests,
+    /// consuming this [HTTPServer](self::HTTPServer) and serving content
+    /// using the added [handlers](self::HTTPHandle).
+    pub fn listen(self, port: u16) -> io::Result<()> {
+        self.listen_addr(("127.0.0.1", port))
+    }
+
+    /// Listen on the given address for incoming requests, e.g. `0.0.0.0:8080`
+    /// to accept connections on all IPv4 interfaces or `[::1]:8080` for IPv6,
+    /// consuming this [HTTPServer](self::HTTPServer) and serving content
+    /// using the added [handlers](self::HTTPHandle).
+    ///
+    /// # Errors
+    ///
+    /// The error of binding to the address is returned as is,
+    /// e.g. with kind [AddrInUse](std::io::ErrorKind::AddrInUse) if the address is already in use.
+    pub fn listen_addr(self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        self.bind_addr(addr)?.run()
+    }
+
+    /// Bind to the given local TCP port, without accepting connections yet,
+    /// returning the port bound to together with the [RunningServer](self::RunningServer)
+    /// which accepts them once [run](self::RunningServer::run).
+    ///
+    /// The port bound to is the given port, unless port 0 is given,
+    /// in which case it is the port picked by the OS.
+    pub fn bind(self, port: u16) -> io::Result<(u16, RunningServer)> {
+        let server = self.bind_addr(("127.0.0.1", port))?;
+        Ok((server.local_addr()?.port(), server))
+    }
+
+    /// Bind to the given address, without accepting connections yet,
+    /// returning the [RunningServer](self::RunningServer) which accepts them once
+    /// [run](self::RunningServer::run).
+    ///
+    /// # Errors
+    ///
+    /// Same as [HTTPServer::listen_addr](self::HTTPServer::listen_addr).
+    pub fn bind_addr(self, addr: impl ToSocketAddrs) -> io::Result<RunningServer> {
+        let listener = TcpListener::bind(addr)?;
+        log::info!("HTTP Server listening at: {}", listener.local_addr()?);
+        self.bind_listener(listener)
+    }
+
+    /// Use the given [Listener](self::Listener) to accept connections from,
+    /// e.g. a [UnixListener](std::os::unix::net::UnixListener),
+    /// returning the [RunningServer](self::RunningServer) which accepts them once
+    /// [run](self::RunningServer::run).
+    ///
+    /// # Errors
+    ///
+    /// The error of making the listener non-blocking is returned as is.
+    pub fn bind_listener<L: Listener>(self, listener: L) -> io::Result<RunningServer<L>> {
+        listener.set_nonblocking(true)?;
+        Ok(RunningServer {
+            server: self,
+            listener,
+        })
+    }
+}
+
+/// Source of the connections accepted by a [RunningServer](self::RunningServer).
+pub trait Listener {
+    /// Connection accepted by this listener.
+    type Stream: Connection;
+
+    /// Accept a pending connection, together with the address of its client if it has one.
+    /// Once non-blocking, fails with [WouldBlock](std::io::ErrorKind::WouldBlock)
+    /// when no connection is pending, rather than waiting for one.
+    fn accept(&self) -> io::Result<(Self::Stream, Option<SocketAddr>)>;
+
+    /// Stop or start waiting for a connection to be pending when accepting one.
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+/// Connection accepted by a [Listener](self::Listener),
+/// from which a request is read and to which its response is written.
+pub trait Connection: Read + Write + Send + 'static {
+    /// Shut down the writing half of the connection,
+    /// signalling the end of the response to the client.
+    fn shutdown_write(&self) -> io::Result<()>;
+
+    /// Set how long reading from the connection can block, or block indefinitely if none,
+    /// used to limit how long a connection kept alive is waited on for its next request.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// TCP stream of this connection, if it is one,
+    /// such that the socket options set on the server can be applied to it.
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> io::Result<(TcpStream, Option<SocketAddr>)> {
+        let (stream, remote_addr) = TcpListener::accept(self)?;
+        Ok((stream, Some(remote_addr)))
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpListener::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl Connection for TcpStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for std::os::unix::net::UnixListener {
+    type Stream = std::os::unix::net::UnixStream;
+
+    fn accept(&self) -> io::Result<(Self::Stream, Option<SocketAddr>)> {
+        let (stream, _) = std::os::unix::net::UnixListener::accept(self)?;
+        Ok((stream, None))
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        std::os::unix::net::UnixListener::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        std::os::unix::net::UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// [HTTPServer](self::HTTPServer) bound to an address,
+/// ready to accept connections on it.
+pub struct RunningServer<L: Listener = TcpListener> {
+    server: HTTPServer,
+    listener: L,
+}
+
+impl RunningServer {
+    /// The address this server is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl<L: Listener> RunningServer<L> {
+    /// Accept incoming requests until shut down,
+    /// serving content using the added [handlers](self::HTTPHandle).
+    ///
+    /// Connections of which the client has no [SocketAddr](std::net::SocketAddr),
+    /// such as those of a Unix socket, are not tracked by the
+    /// [ConnectionTracker](self::ConnectionTracker).
+    pub fn run(mut self) -> io::Result<()> {
+        // the default pool is kept, such that it can be detached on a shutdown timeout
+        let mut pool = None;
+        let mut execute = match self.server.executor {
+            Some(e) => e,
+            None => {
+                let default_pool = ThreadPool::new(self.server.pool_size)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+                let default_pool = Arc::new(default_pool);
+                pool = Some(Arc::clone(&default_pool));
+                HTTPServer::pool_executor(default_pool)
+            }
+        };
+
+        let ctx = Arc::new(self.server.ctx);
+        let pending = Arc::new(AtomicUsize::new(0));
+        let active = Arc::new(AtomicUsize::new(0));
+        let mut next_accept = ctx.now();
+
+        loop {
+            let now = ctx.now();
+            if now < next_accept {
+                // leave new connections queued on the listener until the accept rate allows
+                if shutdown_requested(&mut self.server.shutdown) {
+                    break;
+                }
+                std::thread::sleep(Duration::min(
+                    next_accept - now,
+                    self.server.accept_poll_interval,
+                ));
+                continue;
+            }
+
+            if self.server.pause.is_paused() {
+                // leave new connections queued on the listener until resumed
+                if shutdown_requested(&mut self.server.shutdown) {
+                    break;
+                }
+                std::thread::sleep(self.server.accept_poll_interval);
+                continue;
+            }
+
+            match self.listener.accept() {
+                Ok((mut stream, remote_addr)) => {
+                    if let Some(interval) = self.server.accept_interval {
+                        next_accept = ctx.now() + interval;
+                    }
+                    if let Some(tcp_stream) = stream.tcp_stream() {
+                        if let Err(e) = self.server.stream_options.apply(tcp_stream) {
+                            log::warn!("failed to apply options to accepted connection: {}", e);
+                        }
+                    }
+                    if let Some(max_pending) = self.server.max_pending {
+                        if pending.load(Ordering::SeqCst) >= max_pending {
+                            log::warn!("too many pending connections, rejecting connection");
+                            if let Err(e) = reject_connection(&mut stream, self.server.retry_after)
+                            {
+                                log::debug!("failed to reject connection: {}", e);
+                            }
+                            continue;
+                        }
+                    }
+                    pending.fetch_add(1, Ordering::SeqCst);
+                    let active = ActiveGuard::new(&active);
+
+                    let ctx = Arc::clone(&ctx);
+                    let pending = Arc::clone(&pending);
+                    let connections = self.server.connections.clone();
+                    let read_timeout = self.server.stream_options.read_timeout;
+                    let keep_alive_timeout = self.server.keep_alive_timeout;
+                    execute(Box::new(move || {
+                        let _active = active;
+                        pending.fetch_sub(1, Ordering::SeqCst);
+                        let _tracked = remote_addr.map(|addr| connections.track(addr));
+                        let result = serve_connection(
+                            &ctx,
+                            stream,
+                            remote_addr,
+                            read_timeout,
+                            keep_alive_timeout,
+                            &pending,
+                        );
+                        match result {
+                            Err(e) if is_client_disconnect(&e) => {
+                                log::debug!("client disconnected while handling connection: {}", e);
+                            }
+                            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                                log::warn!("dropped connection of a stalled client: {}", e);
+                            }
+                            Err(e) => log::error!("failed to handle connection: {}", e),
+                            Ok(()) => (),
+                        }
+                    }));
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if shutdown_requested(&mut self.server.shutdown) {
+                        break;
+                    }
+                    std::thread::sleep(self.server.accept_poll_interval);
+                }
+                Err(e) => {
+                    eprintln!("failed to handle connection: encountered IO error: {}", e);
+                }
+            };
+        }
+
+        log::debug!("HTTP Server stopped listening!");
+        ctx.stopping.store(true, Ordering::SeqCst);
+
+        if let Some(timeout) = self.server.shutdown_timeout {
+            let deadline = ctx.now() + timeout;
+            while active.load(Ordering::SeqCst) > 0 && ctx.now() < deadline {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            let remaining = active.load(Ordering::SeqCst);
+            if remaining > 0 {
+                log::warn!(
+                    "shutdown timeout elapsed with {} connection(s) still being handled, no longer waiting on them",
+                    remaining
+                );
+                if let Some(pool) = &pool {
+                    pool.detach();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Counts a connection as being handled, from the moment it is handed to the executor
+// until it is dropped, whether it was handled or dropped by the executor without doing so.
+struct ActiveGuard(Arc<AtomicUsize>);
+
+impl ActiveGuard {
+    fn new(active: &Arc<AtomicUsize>) -> ActiveGuard {
+        active.fetch_add(1, Ordering::SeqCst);
+        ActiveGuard(Arc::clone(active))
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Check without blocking if a graceful shutdown signal was received,
+// unsetting the shutdown channel if it turns out to be broken.
+fn shutdown_requested(shutdown: &mut Option<mpsc::Receiver<()>>) -> bool {
+    if let Some(ref r) = shutdown {
+        match r.try_recv() {
+            Err(mpsc::TryRecvError::Empty) => (),
+            Err(e) => {
+                log::error!(
+                    "graceful shutdown channel was set, but has an unexpected error: {}",
+                    e
+                );
+                *shutdown = None;
+            }
+            Ok(_) => {
+                log::info!("Graceful shutdown signal received, stopping server now...");
+                return true;
+            }
+        }
+    };
+    false
+}
+
+// Whether the error is caused by the client closing the connection early,
+// which is a normal thing for clients to do rather than a server error.
+fn is_client_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+// Respond to a connection which will not be handled,
+// without parsing its request as to keep it cheap.
+fn reject_connection(
+    stream: &mut impl Connection,
+    retry_after: Option<Duration>,
+) -> io::Result<()> {
+    rejection_response(retry_after).write_to(stream)?;
+    stream.flush()?;
+    close_connection(stream)
+}
+
+// Close the connection once responded to, shutting down its writing half first and
+// discarding what the client still sends, up to a bound. Dropping a connection with
+// unread data resets it, which can make its client lose the response.
+fn close_connection(stream: &mut impl Connection) -> io::Result<()> {
+    stream.shutdown_write()?;
+    let deadline = Instant::now() + MAX_DRAIN_TIME;
+    let mut buffer = [0; 4096];
+    let mut drained = 0;
+    while drained < MAX_DRAIN_BYTES {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        stream.set_read_timeout(Some(deadline - now))?;
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => drained += n,
+            // the response is sent already, so whatever went wrong only ends the draining
+            Err(_) => break,
+        }
+    }
+    Ok(())
+}
+
+fn rejection_response(retry_after: Option<Duration>) -> HTTPResponse {
+    let response = HTTPResponse::new(503).with_header("Connection", "close");
+    match retry_after {
+        Some(delay) => {
+            let secs = delay.as_secs() + u64::from(delay.subsec_nanos() > 0);
+            response.with_header("Retry-After", &secs.to_string())
+        }
+        None => response,
+    }
+}
+
+fn validate_path(path: &str) -> Result<(), RouteError> {
+    if !path.starts_with('/') {
+        return Err(RouteError {
+            kind: RouteErrorKind::InvalidPath,
+            message: "path has to start with a '/'",
+        });
+    }
+    if path.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(RouteError {
+            kind: RouteErrorKind::InvalidPath,
+            message: "path cannot contain whitespace or control characters",
+        });
+    }
+    Ok(())
+}
+
+fn create_pattern(method: HTTPMethod, path: &str) -> String {
+    if path.is_empty() {
+        return create_pattern(method, "/");
+    }
+    format!("{} {} HTTP/1.1\r\n", method, path)
+}
+
+// Handle the requests of a connection one after another, for as long as its client
+// keeps it alive and sends its next request within the keep-alive timeout,
+// while no other connection is pending, waiting on the executor to be handled,
+// and the server isn't shutting down.
+fn serve_connection(
+    ctx: &ServerContext,
+    stream: impl Connection,
+    remote_addr: Option<SocketAddr>,
+    read_timeout: Option<Duration>,
+    keep_alive_timeout: Duration,
+    pending: &AtomicUsize,
+) -> io::Result<()> {
+    let mut stream = BufStream(io::BufReader::new(stream));
+    while handle_connection(ctx, &mut stream, remote_addr)? {
+        let deadline = ctx.now() + keep_alive_timeout;
+        loop {
+            let now = ctx.now();
+            if now >= deadline
+                || pending.load(Ordering::SeqCst) > 0
+                || ctx.stopping.load(Ordering::SeqCst)
+            {
+                log::debug!("closing kept alive connection after being idle");
+                return stream.0.get_ref().shutdown_write();
+            }
+            let wait = Duration::min(deadline - now, KEEP_ALIVE_POLL_INTERVAL);
+            stream.0.get_ref().set_read_timeout(Some(wait))?;
+            match stream.fill_buf() {
+                Ok([]) => return Ok(()),
+                Ok(_) => break,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        stream.0.get_ref().set_read_timeout(read_timeout)?;
+    }
+    // explicitly signal the end of the last response to clients which read until
+    // the connection closes, while draining what is left of a request not read entirely
+    close_connection(stream.0.get_mut())
+}
+
+// Connection of which the bytes read past the end of a request are buffered,
+// to be read as the next request on the same connection.
+struct BufStream<S>(io::BufReader<S>);
+
+impl<S: Read> Read for BufStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<S: Read> BufRead for BufStream<S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.0.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.0.consume(amt)
+    }
+}
+
+impl<S: Write> Write for BufStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.get_mut().flush()
+    }
+}
+
+// Handle a single request of the connection, returning whether its client
+// asked for the connection to be kept alive for its next request.
+fn handle_connection(
+    ctx: &ServerContext,
+    mut stream: impl BufRead + Write,
+    remote_addr: Option<SocketAddr>,
+) -> io::Result<bool> {
+    let max_request_line_bytes = ctx
+        .max_request_line_bytes
+        .unwrap_or(DEFAULT_MAX_REQUEST_LINE_BYTES);
+    let max_head_bytes = ctx.max_head_bytes.unwrap_or(DEFAULT_MAX_HEAD_BYTES);
+    let buffer = match read_request_head(
+        &mut stream,
+        max_request_line_bytes,
+        ctx.max_header_line_bytes,
+        max_head_bytes,
+    )? {
+        Some(buffer) => buffer,
+        None => {
+            log::debug!(
+                "431 response for TCP Request with an oversized request line, header line or head"
+            );
+            return write_closing_response(ctx, &mut stream, HTTPResponse::new(431), true);
+        }
+    };
+    let received = ctx.now();
+    if !has_request_line(&buffer) {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+
+    let headers = RequestHeaders::parse(&buffer);
+    // patterns are complete request lines without query, so can be looked up as they are
+    let (request_line, query) = split_query(request_line(&buffer));
+    let head = request_line.starts_with("HEAD ");
+    let mut connection = if ctx.stopping.load(Ordering::SeqCst) {
+        // no longer kept alive once the server is shutting down
+        ConnectionOption::Close
+    } else {
+        connection_option(&request_line, &headers)
+    };
+
+    let upload = ctx.upload_handles.find(&request_line);
+    let max_body_size = ctx.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE);
+    let expects_continue = headers
+        .get("Expect")
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("100-continue"));
+    let body = match upload {
+        // read by the handle instead
+        Some(_) => Ok(Some(Vec::new())),
+        None => read_request_body(&mut stream, &headers, max_body_size, expects_continue),
+    };
+    let body = match body {
+        Ok(Some(body)) => body,
+        Ok(None) => {
+            // a client expecting to be told to continue hasn't sent its body yet
+            let status = if expects_continue { 417 } else { 413 };
+            log::debug!(
+                "{} response for TCP Request with a body over the max size",
+                status
+            );
+            return write_closing_response(ctx, &mut stream, HTTPResponse::new(status), true);
+        }
+        Err(e) => {
+            let status = match e.kind() {
+                io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => 400,
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => 408,
+                _ => return Err(e),
+            };
+            log::debug!(
+                "{} response for TCP Request with an incomplete body: {}",
+                status,
+                e
+            );
+            return write_closing_response(ctx, &mut stream, HTTPResponse::new(status), true);
+        }
+    };
+
+    let mut request = Request {
+        headers,
+        body,
+        query: parse_query(&query),
+        params: PathParams::new(),
+        remote_addr,
+    };
+
+    if let Some((_, handle, params)) = ctx.streaming_handles.find(&request_line) {
+        request.params = params;
+        log::debug!(
+            "TCP Request matched: {:?}",
+            String::from_utf8_lossy(&buffer)
+        );
+        let mut writer = ResponseWriter::new(&mut stream);
+        match handle(&request, &mut writer) {
+            Ok(()) => writer.finish()?,
+            // a response can only be replaced as long as nothing of it was written
+            Err(e) if !writer.status_written => {
+                log::error!("handle failed to respond: {}", e);
+                return write_closing_response(ctx, &mut stream, internal_server_error(), !head);
+            }
+            Err(e) => return Err(e),
+        }
+        // the connection isn't kept alive, as announced by the writer
+        return stream.flush().map(|_| false);
+    }
+
+    let mut response = if let Some((_, handle, params)) = upload {
+        request.params = params;
+        log::debug!(
+            "TCP Request matched: {:?}",
+            String::from_utf8_lossy(&buffer)
+        );
+        let len = match content_length(&request.headers) {
+            Ok(len) => len,
+            Err(e) => {
+                log::debug!("400 response for TCP Request: {}", e);
+                return write_closing_response(ctx, &mut stream, HTTPResponse::new(400), true);
+            }
+        };
+        if expects_continue && len > 0 {
+            write_continue(&mut stream)?;
+        }
+        let mut body = Read::by_ref(&mut stream).take(len as u64);
+        let response = handle(&request, &mut body).unwrap_or_else(|e| {
+            log::error!("handle failed to respond: {}", e);
+            internal_server_error()
+        });
+        if body.limit() > 0 {
+            // what is left of the body can't be told apart from the next request
+            connection = ConnectionOption::Close;
+        }
+        log::debug!("{} response for matched TCP Request", response.status);
+        response
+    } else {
+        let handles = ctx.handles.snapshot();
+        let handle = handles.find(&request_line).or_else(|| {
+            let path = request_line.strip_prefix("HEAD ")?;
+            handles.find(&format!("GET {}", path))
+        });
+        match handle {
+            Some((route, handle, params)) => {
+                request.params = params;
+                log::debug!(
+                    "TCP Request matched: {:?}",
+                    String::from_utf8_lossy(&buffer)
+                );
+                let start = ctx.now();
+                let respond = || {
+                    handle(&request).unwrap_or_else(|e| {
+                        log::error!("handle failed to respond: {}", e);
+                        internal_server_error()
+                    })
+                };
+                let response = match &ctx.in_flight {
+                    Some(in_flight) if request_line.starts_with("GET ") => {
+                        coalesce(in_flight, &format!("{}?{}", request_line, query), respond)
+                    }
+                    _ => respond(),
+                };
+                if let Some(latencies) = &ctx.latencies {
+                    latencies.record(route, ctx.now().saturating_duration_since(start));
+                }
+                log::debug!("{} response for matched TCP Request", response.status);
+                response
+            }
+            None => match allowed_methods(ctx, &handles, &request_line) {
+                Some(allow) => {
+                    log::debug!(
+                        "405 response for TCP Request: {:?}",
+                        String::from_utf8_lossy(&buffer)
+                    );
+                    HTTPResponse::new(405).with_header("Allow", &allow)
+                }
+                None => match &ctx.not_found_handle {
+                    Some(handle) => {
+                        log::debug!(
+                            "TCP Request not found: {:?}",
+                            String::from_utf8_lossy(&buffer)
+                        );
+                        let response = handle(&request).unwrap_or_else(|e| {
+                            log::error!("not found handle failed to respond: {}", e);
+                            internal_server_error()
+                        });
+                        log::debug!("{} response for not found TCP Request", response.status);
+                        response
+                    }
+                    None => {
+                        log::debug!(
+                            "404 response for TCP Request: {:?}",
+                            String::from_utf8_lossy(&buffer)
+                        );
+                        not_found()
+                    }
+                },
+            },
+        }
+    };
+    if response.has_body() && response.content_type.is_none() {
+        response.content_type = ctx.default_content_type.clone();
+    }
+    if ctx.empty_200_as_204 && response.status == 200 && !response.has_body() {
+        response.status = 204;
+    }
+    if response.body_stream.is_some() && request_line.ends_with(" HTTP/1.0\r\n") {
+        // HTTP/1.0 clients don't support the chunked transfer encoding
+        response.close_delimited = true;
+        connection = ConnectionOption::Close;
+    }
+    match connection {
+        ConnectionOption::KeepAlive => response.set_header("Connection", "keep-alive"),
+        ConnectionOption::Persistent => (),
+        ConnectionOption::Close => response.set_header("Connection", "close"),
+    }
+    if connection != ConnectionOption::Close && !delimits_itself(&response) {
+        // otherwise the client would only know the response ended once the connection closes
+        response.set_header("Content-Length", "0");
+    }
+    if !ctx.middlewares.is_empty() {
+        let (method, path, _) = split_request_line(&request_line).unwrap_or_default();
+        let info = RequestInfo {
+            method: String::from(method),
+            path: String::from(path),
+            remote_addr,
+            duration: ctx.now().saturating_duration_since(received),
+        };
+        for middleware in &ctx.middlewares {
+            middleware(&info, &response);
+        }
+    }
+
+    write_response(ctx, &mut stream, response, !head)?;
+    Ok(connection != ConnectionOption::Close)
+}
+
+// Write a response after which the connection is closed, announcing so to the client.
+fn write_closing_response(
+    ctx: &ServerContext,
+    stream: &mut impl Write,
+    mut response: HTTPResponse,
+    with_body: bool,
+) -> io::Result<bool> {
+    response.set_header("Connection", "close");
+    write_response(ctx, stream, response, with_body).map(|_| false)
+}
+
+// Whether the client can tell where the response ends without the connection being closed,
+// which is the case for responses which have a body or a status which never has one.
+fn delimits_itself(response: &HTTPResponse) -> bool {
+    response.has_body()
+        || response
+            .headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+        || (100..200).contains(&response.status)
+        || response.status == 204
+        || response.status == 304
+}
+
+// Option of a client for the connection of its request, once responded to.
+#[derive(Debug, PartialEq)]
+enum ConnectionOption {
+    // kept alive as the client asked for it explicitly
+    KeepAlive,
+    // kept alive as HTTP/1.1 connections are by default
+    Persistent,
+    Close,
+}
+
+// Read whether the client asked for the connection to be kept alive or to be closed,
+// the latter being implied for HTTP/1.0 requests, unless they ask for it to be kept alive.
+// Connections of other requests are kept alive, unless the client asks for them to be closed.
+fn connection_option(request_line: &str, headers: &RequestHeaders) -> ConnectionOption {
+    let options = headers.get("Connection").unwrap_or_default();
+    let has_option = |option: &str| {
+        options
+            .split(',')
+            .any(|o| o.trim().eq_ignore_ascii_case(option))
+    };
+    if has_option("close") {
+        ConnectionOption::Close
+    } else if has_option("keep-alive") {
+        ConnectionOption::KeepAlive
+    } else if request_line.ends_with(" HTTP/1.0\r\n") {
+        ConnectionOption::Close
+    } else {
+        ConnectionOption::Persistent
+    }
+}
+
+// List the methods for which a handle is added for the path of the request line,
+// as the value of an `Allow` header, or none if the path has no handle at all.
+fn allowed_methods(
+    ctx: &ServerContext,
+    handles: &Routes<HTTPHandle>,
+    request_line: &str,
+) -> Option<String> {
+    let (_, path) = route_key(request_line)?.split_once(' ')?;
+    let mut methods = Vec::new();
+    handles.add_methods(path, &mut methods);
+    ctx.streaming_handles.add_methods(path, &mut methods);
+    ctx.upload_handles.add_methods(path, &mut methods);
+    let has_handle = |method: &HTTPMethod| methods.contains(&method.to_string().as_str());
+    let allowed: Vec<String> = [
+        HTTPMethod::Get,
+        HTTPMethod::Post,
+        HTTPMethod::Put,
+        HTTPMethod::Delete,
+        HTTPMethod::Patch,
+        HTTPMethod::Head,
+        HTTPMethod::Options,
+    ]
+    .iter()
+    .filter(|method| match method {
+        HTTPMethod::Head => has_handle(&HTTPMethod::Head) || has_handle(&HTTPMethod::Get),
+        method => has_handle(method),
+    })
+    .map(HTTPMethod::to_string)
+    .collect();
+    match allowed.is_empty() {
+        true => None,
+        false => Some(allowed.join(", ")),
+    }
+}
+
+// Split a request line (or pattern) in its method, target and the rest of the line.
+fn split_request_line(line: &str) -> Option<(&str, &str, &str)> {
+    let (method, rest) = line.split_once(' ')?;
+    let (target, rest) = rest.split_once(' ')?;
+    Some((method, target, rest))
+}
+
+// Match a path against the path of a handle, segment by segment,
+// capturing the segments matched by its `:name` segments,
+// and the remainder of the path matched by a trailing `*` segment.
+fn match_path(pattern: &str, path: &str) -> Option<PathParams> {
+    let mut params = PathParams::new();
+    let mut segments = path.split('/');
+    let mut pattern_segments = pattern.split('/').peekable();
+    while let Some(pattern_segment) = pattern_segments.next() {
+        if pattern_segment == "*" && pattern_segments.peek().is_none() {
+            let rest: Vec<_> = segments.by_ref().collect();
+            if rest.is_empty() {
+                return None;
+            }
+            params.insert(String::from("*"), url_decode(&rest.join("/")));
+            break;
+        }
+        let segment = segments.next()?;
+        match pattern_segment.strip_prefix(':') {
+            Some(name) if !segment.is_empty() => {
+                params.insert(String::from(name), url_decode(segment));
+            }
+            Some(_) => return None,
+            None if pattern_segment == segment => (),
+            None => return None,
+        }
+    }
+    match segments.next() {
+        Some(_) => None,
+        None => Some(params),
+    }
+}
+
+// Resolve a path relative to the root directory,
+// or None if it tries to escape the root directory.
+fn resolve_file(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut resolved = root.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(segment) => resolved.push(segment),
+            Component::CurDir => (),
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(resolved)
+}
+
+// Guess the content type of a file from its extension.
+fn guess_content_type(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    Some(match extension.as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "json" => "application/json",
+        "txt" => "text/plain",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "svg" => "image/svg+xml",
+        _ => return None,
+    })
+}
+
+// Response of a request being handled, shared with identical requests waiting for it.
+// Once landed it holds None if the response can't be shared, as its body is streamed.
+#[derive(Default)]
+struct Flight {
+    response: Mutex<Option<Option<HTTPResponse>>>,
+    done: Condvar,
+}
+
+// Respond using the given function, unless an identical request is being responded to already,
+// in which case its response is waited for and used instead.
+fn coalesce(
+    in_flight: &Mutex<HashMap<String, Arc<Flight>>>,
+    key: &str,
+    respond: impl FnOnce() -> HTTPResponse,
+) -> HTTPResponse {
+    let flight = {
+        let mut in_flight = in_flight.lock().unwrap();
+        match in_flight.get(key) {
+            Some(flight) => Some(Arc::clone(flight)),
+            None => {
+                in_flight.insert(String::from(key), Arc::new(Flight::default()));
+                None
+            }
+        }
+    };
+    if let Some(flight) = flight {
+        log::debug!("coalesced TCP Request with an identical one in flight");
+        let mut response = flight.response.lock().unwrap();
+        let landed = loop {
+            match &*response {
+                Some(landed) => break landed.clone(),
+                None => response = flight.done.wait(response).unwrap(),
+            }
+        };
+        drop(response);
+        return landed.unwrap_or_else(respond);
+    }
+
+    // waiting requests are answered even if responding panics
+    struct Landing<'a> {
+        in_flight: &'a Mutex<HashMap<String, Arc<Flight>>>,
+        key: &'a str,
+        response: Option<Option<HTTPResponse>>,
+    }
+    impl Drop for Landing<'_> {
+        fn drop(&mut self) {
+            let flight = self.in_flight.lock().unwrap().remove(self.key);
+            if let Some(flight) = flight {
+                let response = self
+                    .response
+                    .take()
+                    .unwrap_or_else(|| Some(internal_server_error()));
+                *flight.response.lock().unwrap() = Some(response);
+                flight.done.notify_all();
+            }
+        }
+    }
+    let mut landing = Landing {
+        in_flight,
+        key,
+        response: None,
+    };
+    let response = respond();
+    landing.response = Some(match response.body_stream {
+        Some(_) => None,
+        None => Some(response.clone()),
+    });
+    response
+}
+
+fn internal_server_error() -> HTTPResponse {
+    HTTPResponse::new(500).with_content("Internal Server Error")
+}
+
+fn not_found() -> HTTPResponse {
+    HTTPResponse::new(404).with_content(HTTP_CONTENT_404)
+}
+
+// Read the head of a request, up to and including the empty line ending it,
+// or None if its request line, one of its header lines or the head as a whole
+// turns out to be longer than the given max, as soon as it does.
+// A head cut short by the client closing the connection is returned as far as it was received.
+fn read_request_head(
+    stream: &mut impl BufRead,
+    max_request_line: usize,
+    max_header_line: Option<usize>,
+    max_head: usize,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut buffer = Vec::new();
+    // start of the header line being received, once past the request line
+    let mut header_line_start = None;
+    let mut retries = 0;
+    loop {
+        let start = buffer.len();
+        match stream.fill_buf() {
+            Ok([]) => break,
+            Ok(available) => buffer.extend_from_slice(available),
+            Err(e) => match e.kind() {
+                // retry a max amount of times
+                io::ErrorKind::WouldBlock if retries < 16 => {
+                    retries += 1;
+                    std::thread::sleep(Duration::from_millis(50));
+                    continue;
+                }
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        match buffer.is_empty() {
+                            true => "timed out waiting for the request",
+                            false => "timed out waiting for the rest of the request head",
+                        },
+                    ));
+                }
+                io::ErrorKind::UnexpectedEof => break,
+                _ => return Err(e),
+            },
+        }
+        // consume no more than the head, leaving what follows it
+        // to be read as its body or as the next request of the connection
+        let from = start.saturating_sub(3);
+        let end = buffer[from..]
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map_or(buffer.len(), |i| from + i + 4);
+        stream.consume(end - start);
+        buffer.truncate(end);
+        let request_line_len = buffer
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .unwrap_or(buffer.len());
+        if request_line_len > max_request_line || buffer.len() > max_head {
+            return Ok(None);
+        }
+        if let Some(max) = max_header_line {
+            // header lines are measured as they arrive, without their line ending,
+            // including the one of which the rest is yet to be received
+            for (i, _) in buffer
+                .iter()
+                .enumerate()
+                .skip(start)
+                .filter(|(_, b)| **b == b'\n')
+            {
+                if header_line_start
+                    .is_some_and(|line_start| line_len(&buffer[line_start..i]) > max)
+                {
+                    return Ok(None);
+                }
+                header_line_start = Some(i + 1);
+            }
+            if header_line_start.is_some_and(|line_start| line_len(&buffer[line_start..]) > max) {
+                return Ok(None);
+            }
+        }
+        if buffer.ends_with(b"\r\n\r\n") {
+            break;
+        }
+    }
+    Ok(Some(buffer))
+}
+
+// Read the body of a request, of as many bytes as its Content-Length,
+// following the head of the request which was read already.
+//
+// None is returned, without reading the body, if the Content-Length is over the given max.
+// An InvalidData error is returned for an invalid Content-Length,
+// and an UnexpectedEof error if the connection is closed before the body is complete.
+// A client which expects to be told to continue is told so before its body is read.
+fn read_request_body(
+    stream: &mut (impl Read + Write),
+    headers: &RequestHeaders,
+    max: usize,
+    expects_continue: bool,
+) -> io::Result<Option<Vec<u8>>> {
+    let len = content_length(headers)?;
+    if len > max {
+        return Ok(None);
+    }
+    if expects_continue && len > 0 {
+        write_continue(stream)?;
+    }
+
+    // grown as the body is read, rather than trusting the Content-Length upfront
+    let mut body = Vec::new();
+    Read::by_ref(stream)
+        .take(len as u64)
+        .read_to_end(&mut body)?;
+    if body.len() < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok(Some(body))
+}
+
+// Length of the body of a request as declared by its Content-Length, 0 if it has none,
+// or an InvalidData error if it isn't a valid length.
+fn content_length(headers: &RequestHeaders) -> io::Result<usize> {
+    match headers.get("Content-Length") {
+        Some(len) => len
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid Content-Length")),
+        None => Ok(0),
+    }
+}
+
+// Tell a client which expects it to continue with sending the body of its request.
+fn write_continue(stream: &mut impl Write) -> io::Result<()> {
+    stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    stream.flush()
+}
+
+// Whether the (partial) head starts with a request line, being a method,
+// a target and an HTTP version separated by a space, e.g. `GET / HTTP/1.1`.
+fn has_request_line(head: &[u8]) -> bool {
+    let end = head.windows(2).position(|w| w == b"\r\n");
+    let line = &head[..end.unwrap_or(head.len())];
+    let parts: Vec<&str> = match std::str::from_utf8(line) {
+        Ok(line) => line.split(' ').collect(),
+        Err(_) => return false,
+    };
+    match parts[..] {
+        [method, target, version] => {
+            !method.is_empty() && !target.is_empty() && version.starts_with("HTTP/")
+        }
+        _ => false,
+    }
+}
+
+// Return the request line of a (partial) request head, including its line ending,
+// in the same form as the pattern of a handle. The method token is uppercased,
+// as some clients send it in lowercase, while the target is left as it is.
+fn request_line(head: &[u8]) -> Cow<'_, str> {
+    let end = head
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .map_or(head.len(), |i| i + 2);
+    let line = String::from_utf8_lossy(&head[..end]);
+    let method_end = line.find(' ').unwrap_or(line.len());
+    if !line[..method_end].bytes().any(|b| b.is_ascii_lowercase()) {
+        return line;
+    }
+    let mut line = line.into_owned();
+    line[..method_end].make_ascii_uppercase();
+    Cow::Owned(line)
+}
+
+// Split the query from the target of a request line,
+// returning the request line without it, together with the query.
+fn split_query(line: Cow<'_, str>) -> (Cow<'_, str>, String) {
+    let target_start = match line.find(' ') {
+        Some(i) => i + 1,
+        None => return (line, String::new()),
+    };
+    let target_end = line[target_start..]
+        .find(' ')
+        .map_or(line.len(), |i| target_start + i);
+    match line[target_start..target_end].find('?') {
+        Some(i) => {
+            let query_start = target_start + i;
+            let query = String::from(&line[query_start + 1..target_end]);
+            let line = format!("{}{}", &line[..query_start], &line[target_end..]);
+            (Cow::Owned(line), query)
+        }
+        None => (line, String::new()),
+    }
+}
+
+// Parse a query string into its URL-decoded parameters.
+fn parse_query(query: &str) -> QueryParams {
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            (url_decode(name), url_decode(value))
+        })
+        .collect()
+}
+
+// Decode `+` as a space and `%XX` as the byte it encodes,
+// leaving invalid escapes as they are.
+fn url_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            // from_str_radix alone would accept a sign, e.g. `%+A`
+            b'%' => match s
+                .get(i + 1..i + 3)
+                .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 2;
+                }
+                None => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Transform the response and write it as a whole, then flush it.
+// The body is left out when responding to a HEAD request,
+// but still counted in the Content-Length of the response.
+fn write_response(
+    ctx: &ServerContext,
+    stream: &mut impl Write,
+    mut response: HTTPResponse,
+    with_body: bool,
+) -> io::Result<()> {
+    for (name, value) in &ctx.global_headers {
+        if !response
+            .headers
+            .iter()
+            .any(|(n, _)| n.eq_ignore_ascii_case(name))
+        {
+            response.headers.push((name.clone(), value.clone()));
+        }
+    }
+    for transform in &ctx.response_transformers {
+        transform(&mut response);
+    }
+    if let Some(body) = &response.body_stream {
+        let mut head = Vec::new();
+        response.write_head(&mut head)?;
+        stream.write_all(&head)?;
+        if with_body {
+            response.write_body_stream(stream, body)?;
+        }
+        return stream.flush();
+    }
+    RESPONSE_BUFFER.with(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        buffer.clear();
+        response.write_to(&mut *buffer)?;
+        let body_len = response.content.as_ref().map_or(0, |c| c.len());
+        if !with_body {
+            let head_len = buffer.len() - body_len;
+            buffer.truncate(head_len);
+        }
+        let result = match ctx.max_send_rate {
+            Some(rate) if with_body => {
+                let (head, body) = buffer.split_at(buffer.len() - body_len);
+                stream
+                    .write_all(head)
+                    .and_then(|_| write_paced(stream, body, rate))
+            }
+            _ => stream.write_all(&buffer),
+        };
+        // do not hold on to the memory of an exceptionally large response
+        buffer.shrink_to(RESPONSE_BUFFER_MAX_CAPACITY);
+        result
+    })?;
+    stream.flush()
+}
+
+// Write the data in parts of about a tenth of the rate,
+// sleeping after each part until the rate is no longer exceeded.
+fn write_paced(stream: &mut impl Write, data: &[u8], bytes_per_sec: u64) -> io::Result<()> {
+    let start = Instant::now();
+    let part_size = (bytes_per_sec / 10).max(1) as usize;
+    let mut sent = 0;
+    for part in data.chunks(part_size) {
+        stream.write_all(part)?;
+        stream.flush()?;
+        sent += part.len() as u64;
+        let due = Duration::from_secs_f64(sent as f64 / bytes_per_sec as f64);
+        if let Some(ahead) = due.checked_sub(start.elapsed()) {
+            std::thread::sleep(ahead);
+        }
+    }
+    Ok(())
+}
+
+// Length of a line, excluding its line ending.
+fn line_len(line: &[u8]) -> usize {
+    line.strip_suffix(b"\r").unwrap_or(line).len()
+}
+
+thread_local! {
+    // Buffer reused by a thread for all the responses it writes,
+    // saving an allocation per response.
+    static RESPONSE_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+const RESPONSE_BUFFER_MAX_CAPACITY: usize = 64 * 1024;
+
+const DEFAULT_MAX_REQUEST_LINE_BYTES: usize = 8 * 1024;
+
+const DEFAULT_MAX_HEAD_BYTES: usize = 64 * 1024;
+
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+
+const DEFAULT_ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+const DEFAULT_POOL_SIZE: usize = 4;
+
+const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const KEEP_ALIVE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+const MAX_DRAIN_BYTES: usize = 64 * 1024;
+
+const MAX_DRAIN_TIME: Duration = Duration::from_millis(100);
+
+const HTTP_CONTENT_404: &str = r#"<!DOCTYPE html>
+<html lang="en">
+  <head>
+    <meta charset="utf-8">
+    <title>Hello!</title>
+  </head>
+  <body>
+    <h1>Oops!</h1>
+    <p>Sorry, I don't know what you're asking for.</p>
+  </body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+
+    #[test]
+    fn test_create_pattern() {
+        assert_eq!(
+            String::from("GET / HTTP/1.1\r\n"),
+            create_pattern(HTTPMethod::Get, ""),
+        );
+        assert_eq!(
+            String::from("GET / HTTP/1.1\r\n"),
+            create_pattern(HTTPMethod::Get, "/"),
+        );
+        assert_eq!(
+            String::from("POST / HTTP/1.1\r\n"),
+            create_pattern(HTTPMethod::Post, "/"),
+        );
+        assert_eq!(
+            String::from("POST /foo/bar HTTP/1.1\r\n"),
+            create_pattern(HTTPMethod::Post, "/foo/bar"),
+        );
+        assert_eq!(
+            String::from("PUT /foo HTTP/1.1\r\n"),
+            create_pattern(HTTPMethod::Put, "/foo"),
+        );
+        assert_eq!(
+            String::from("DELETE /foo HTTP/1.1\r\n"),
+            create_pattern(HTTPMethod::Delete, "/foo"),
+        );
+        assert_eq!(
+            String::from("PATCH /foo HTTP/1.1\r\n"),
+            create_pattern(HTTPMethod::Patch, "/foo"),
+        );
+        assert_eq!(
+            String::from("HEAD / HTTP/1.1\r\n"),
+            create_pattern(HTTPMethod::Head, ""),
+        );
+        assert_eq!(
+            String::from("OPTIONS /foo HTTP/1.1\r\n"),
+            create_pattern(HTTPMethod::Options, "/foo"),
+        );
+        // simple, not even path validation
+        assert_eq!(
+            String::from("POST 123_invalid@path-yeah HTTP/1.1\r\n"),
+            create_pattern(HTTPMethod::Post, "123_invalid@path-yeah"),
+        );
+    }
+
+    #[test]
+    fn test_routes() {
+        let mut routes: Routes<u8> = vec![
+            (create_pattern(HTTPMethod::Get, "/users"), 1),
+            (create_pattern(HTTPMethod::Get, "/users/:id"), 2),
+            (create_pattern(HTTPMethod::Post, "/users/:id"), 3),
+            (create_pattern(HTTPMethod::Get, "/*"), 4),
+        ]
+        .into_iter()
+        .collect();
+        let find = |routes: &Routes<u8>, method, path| {
+            routes
+                .find(&create_pattern(method, path))
+                .map(|(_, handle, _)| *handle)
+        };
+        assert_eq!(Some(1), find(&routes, HTTPMethod::Get, "/users"));
+        assert_eq!(Some(2), find(&routes, HTTPMethod::Get, "/users/42"));
+        assert_eq!(Some(3), find(&routes, HTTPMethod::Post, "/users/42"));
+        assert_eq!(Some(4), find(&routes, HTTPMethod::Get, "/users/42/posts"));
+        assert_eq!(None, find(&routes, HTTPMethod::Put, "/users/42"));
+        // the same routes match HTTP/1.0 requests, but none of other versions
+        let find_line = |line: &str| routes.find(line).map(|(route, handle, _)| (route, *handle));
+        assert_eq!(
+            Some(("GET /users", 1)),
+            find_line("GET /users HTTP/1.0\r\n")
+        );
+        assert_eq!(
+            Some(("GET /users/:id", 2)),
+            find_line("GET /users/42 HTTP/1.0\r\n")
+        );
+        assert_eq!(None, find_line("GET /users HTTP/2.0\r\n"));
+        assert_eq!(None, find_line("GET /users\r\n"));
+        let mut methods = Vec::new();
+        routes.add_methods("/users/42", &mut methods);
+        methods.sort_unstable();
+        assert_eq!(vec!["GET", "GET", "POST"], methods);
+
+        // overwriting a route doesn't match it twice, removing it no longer at all
+        assert_eq!(
+            Some(2),
+            routes.insert(&create_pattern(HTTPMethod::Get, "/users/:id"), 5)
+        );
+        assert_eq!(
+            1,
+            routes.parameterized["GET"]
+                .iter()
+                .filter(|p| p.contains("/:id"))
+                .count()
+        );
+        assert_eq!(Some(5), find(&routes, HTTPMethod::Get, "/users/42"));
+        assert_eq!(
+            Some(5),
+            routes.remove(&create_pattern(HTTPMethod::Get, "/users/:id"))
+        );
+        assert_eq!(Some(4), find(&routes, HTTPMethod::Get, "/users/42"));
+        assert_eq!(Some(3), find(&routes, HTTPMethod::Post, "/users/42"));
+        assert_eq!(3, routes.handles.len());
+    }
+
+    #[test]
+    fn test_try_add_handle() {
+        let mut server = HTTPServer::new();
+        assert_eq!(
+            Ok(()),
+            server.try_add_handle(
+                HTTPMethod::Get,
+                "/foo/bar",
+                Box::new(|_| Ok(HTTPResponse::new(200))),
+            ),
+        );
+        assert!(server
+            .ctx
+            .handles
+            .snapshot()
+            .find(&create_pattern(HTTPMethod::Get, "/foo/bar"))
+            .is_some());
+
+        for path in ["foo/bar", "", "/foo bar", "/foo\tbar", "/foo\r\n"] {
+            let result = server.try_add_handle(
+                HTTPMethod::Get,
+                path,
+                Box::new(|_| Ok(HTTPResponse::new(200))),
+            );
+            assert_eq!(
+                RouteErrorKind::InvalidPath,
+                result.unwrap_err().kind,
+                "path: {:?}",
+                path
+            );
+        }
+        assert_eq!(1, server.ctx.handles.snapshot().handles.len());
+    }
+
+    #[test]
+    fn test_request_headers() {
+        let headers = RequestHeaders::parse(
+            b"GET / HTTP/1.1\r\n\
+            Host: localhost:7878\r\n\
+            User-Agent: curl/7.64.1\r\n\
+            Accept: text/html\r\n\
+            malformed\r\n\
+            accept:  application/json \r\n\
+            \r\n\
+            Ignored: body",
+        );
+        let expected: HashMap<String, String> = [
+            ("host", "localhost:7878"),
+            ("user-agent", "curl/7.64.1"),
+            ("accept", "text/html, application/json"),
+        ]
+        .iter()
+        .map(|(name, value)| (String::from(*name), String::from(*value)))
+        .collect();
+        assert_eq!(expected, headers.headers);
+
+        assert_eq!(Some("localhost:7878"), headers.get("Host"));
+        assert_eq!(Some("curl/7.64.1"), headers.get("user-agent"));
+        assert_eq!(Some("text/html, application/json"), headers.get("ACCEPT"));
+        assert_eq!(None, headers.get("Ignored"));
+    }
+
+    #[test]
+    fn test_reason_phrase() {
+        assert_eq!(
+            "HTTP/1.1 404 Not Found\r\n\r\n",
+            HTTPResponse::new(404).to_string()
+        );
+        assert_eq!("OK", reason_phrase(200));
+        assert_eq!("Service Unavailable", reason_phrase(503));
+        // the reason phrase is optional, but the space preceding it is not
+        assert_eq!("", reason_phrase(299));
+        assert_eq!("HTTP/1.1 299 \r\n\r\n", HTTPResponse::new(299).to_string());
+    }
+
+    #[test]
+    fn test_http_response_to_string_no_content() {
+        assert_eq!(
+            String::from("HTTP/1.1 403 Forbidden\r\n\r\n"),
+            format!("{}", HTTPResponse::new(403)),
+        );
+    }
+
+    #[test]
+    fn test_http_response_to_string_with_content() {
+        assert_eq!(
+            String::from("HTTP/1.1 200 OK\r\nContent-Length: 13\r\n\r\nHello, World!"),
+            format!("{}", HTTPResponse::new(200).with_content("Hello, World!")),
+        );
+    }
+
+    #[test]
+    fn test_http_response_write_to() -> io::Result<()> {
+        for (response, expected) in [
+            (HTTPResponse::new(403), "HTTP/1.1 403 Forbidden\r\n\r\n"),
+            (
+                HTTPResponse::new(200).with_content("Hello, World!"),
+                "HTTP/1.1 200 OK\r\nContent-Length: 13\r\n\r\nHello, World!",
+            ),
+            (
+                HTTPResponse::new(200)
+                    .with_content("<p>Hi!</p>")
+                    .with_content_type("text/html"),
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 10\r\n\r\n<p>Hi!</p>",
+            ),
+        ] {
+            let mut buffer = Vec::new();
+            response.write_to(&mut buffer)?;
+            assert_eq!(expected.as_bytes(), &buffer[..]);
+            assert_eq!(expected, response.to_string());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_http_response_with_bytes() -> io::Result<()> {
+        let bytes = vec![0xca, 0xfe, 0x00, 0xba, 0xbe];
+        let mut expected = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n".to_vec();
+        expected.extend_from_slice(&bytes);
+
+        let mut buffer = Vec::new();
+        HTTPResponse::new(200)
+            .with_bytes(bytes.clone())
+            .write_to(&mut buffer)?;
+        assert_eq!(expected, buffer);
+
+        let (port, tx, server) = spawn_server(move |server| {
+            server.add_handle(
+                HTTPMethod::Get,
+                "/bytes",
+                Box::new(move |_| Ok(HTTPResponse::new(200).with_bytes(bytes.clone()))),
+            );
+        })?;
+        let mut stream = connect(port)?;
+        stream.write_all(request(HTTPMethod::Get, "/bytes").as_bytes())?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let mut expected =
+            b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 5\r\n\r\n".to_vec();
+        expected.extend_from_slice(&[0xca, 0xfe, 0x00, 0xba, 0xbe]);
+        assert_eq!(expected, response);
+
+        tx.send(()).unwrap();
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_http_response_with_vary() {
+        assert_eq!(
+            String::from("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nVary: Accept-Language, Accept\r\nContent-Length: 2\r\n\r\nHi"),
+            HTTPResponse::new(200)
+                .with_content("Hi")
+                .with_content_type("text/plain")
+                .with_vary("Accept-Language")
+                .with_vary("Accept")
+                .with_vary("accept")
+                .to_string(),
+        );
+        // a negotiated response can vary on a second header as well
+        assert_eq!(
+            String::from("HTTP/1.1 200 OK\r\nVary: Accept-Language, Accept-Encoding\r\nContent-Length: 6\r\n\r\nHallo!"),
+            HTTPResponse::by_language("nl", &HashMap::new(), "Hallo!")
+                .with_vary("Accept-Encoding")
+                .to_string(),
+        );
+        // a response which isn't negotiated doesn't vary
+        assert!(!HTTPResponse::new(200)
+            .with_content("Hi")
+            .with_content_type("text/plain")
+            .to_string()
+            .contains("Vary"));
+    }
+
+    #[test]
+    fn test_http_response_with_header() {
+        assert_eq!(
+            String::from("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nX-Request-Id: 42\r\nSet-Cookie: a=1\r\nSet-Cookie: b=2\r\nContent-Length: 2\r\n\r\nHi"),
+            HTTPResponse::new(200)
+                .with_content("Hi")
+                .with_content_type("text/plain")
+                .with_header("X-Request-Id", "42")
+                .with_header("Set-Cookie", "a=1")
+                .with_header("Set-Cookie", "b=2")
+                .to_string(),
+        );
+        // an explicit Content-Length is preferred over the computed one
+        assert_eq!(
+            String::from("HTTP/1.1 200 OK\r\ncontent-length: 1\r\n\r\nHi"),
+            HTTPResponse::new(200)
+                .with_content("Hi")
+                .with_header("content-length", "1")
+                .to_string(),
+        );
+    }
+
+    #[test]
+    fn test_http_response_from_parts() {
+        assert_eq!(
+            String::from("HTTP/1.1 403 Forbidden\r\n\r\n"),
+            HTTPResponse::from_parts(403, None).to_string(),
+        );
+        assert_eq!(
+            String::from("HTTP/1.1 200 OK\r\nContent-Length: 13\r\n\r\nHello, World!"),
+            HTTPResponse::from_parts(200, Some("Hello, World!")).to_string(),
+        );
+    }
+
+    #[test]
+    fn test_http_response_json() {
+        let body = r#"{"name":"café"}"#;
+        let response = HTTPResponse::json(201, body).to_string();
+        assert_eq!(
+            format!(
+                "HTTP/1.1 201 Created\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+            response
+        );
+        // counted in bytes rather than chars
+        assert!(response.contains("Content-Length: 16\r\n"));
+    }
+
+    #[test]
+    fn test_http_response_by_language() {
+        let variants: HashMap<String, String> = [("en", "Hello!"), ("fr", "Bonjour !")]
+            .iter()
+            .map(|(language, content)| (String::from(*language), String::from(*content)))
+            .collect();
+
+        for (accept_language, expected) in [
+            ("en", "Hello!"),
+            ("fr", "Bonjour !"),
+            ("fr-BE, en;q=0.8", "Bonjour !"),
+            ("fr;q=0.5, en;q=0.9", "Hello!"),
+            ("fr;q=0, en;q=0.1", "Hello!"),
+            ("de", "Hallo!"),
+            ("", "Hallo!"),
+        ] {
+            assert_eq!(
+                HTTPResponse::new(200)
+                    .with_content(expected)
+                    .with_vary("Accept-Language")
+                    .to_string(),
+                HTTPResponse::by_language(accept_language, &variants, "Hallo!").to_string(),
+                "Accept-Language: {}",
+                accept_language,
+            );
+        }
+    }
+
+    #[test]
+    fn test_http_response_by_type() {
+        let variants = [
+            ("text/html; charset=utf-8", "<p>Hi!</p>"),
+            ("application/json", r#"{"greeting":"Hi!"}"#),
+        ];
+        let default = ("text/plain", "Hi!");
+
+        for (accept, (content_type, content)) in [
+            ("text/html", variants[0]),
+            ("application/json", variants[1]),
+            ("application/json;q=0.5, text/html", variants[0]),
+            ("text/html;q=0.5, application/json;q=0.9", variants[1]),
+            ("application/*", variants[1]),
+            ("image/png, */*;q=0.1", variants[0]),
+            ("text/html;q=0", default),
+            ("image/png", default),
+            ("", default),
+        ] {
+            assert_eq!(
+                HTTPResponse::new(200)
+                    .with_content(content)
+                    .with_content_type(content_type)
+                    .with_vary("Accept")
+                    .to_string(),
+                HTTPResponse::by_type(accept, &variants, default).to_string(),
+                "Accept: {}",
+                accept,
+            );
+        }
+        assert!(
+            HTTPResponse::by_type("application/json", &variants, default)
+                .to_string()
+                .contains("\r\nVary: Accept\r\n")
+        );
+    }
+
+    #[derive(Debug, Default)]
+    struct ReadWriteMock {
+        data_to_read: String,
+        written_data: String,
+        written_data_flushed: String,
+        read_error: Option<io::ErrorKind>,
+        write_error: Option<io::ErrorKind>,
+        write_shut_down: std::cell::Cell<bool>,
+    }
+
+    impl ReadWriteMock {
+        fn clear(&mut self) {
+            self.data_to_read.clear();
+            self.written_data.clear();
+            self.written_data_flushed.clear();
+        }
+    }
+
+    impl io::Read for ReadWriteMock {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data_to_read.is_empty() {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            let len = usize::min(buf.len(), self.data_to_read.len());
+            let slice = self.data_to_read.as_bytes();
+            buf[..len].copy_from_slice(&slice[..len]);
+            self.data_to_read = String::from(match std::str::from_utf8(&slice[len..]) {
+                Ok(v) => v,
+                Err(_) => {
+                    return Err(io::Error::from(io::ErrorKind::InvalidInput));
+                }
+            });
+            Ok(len)
+        }
+    }
+
+    impl io::BufRead for ReadWriteMock {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            if let Some(kind) = self.read_error {
+                return Err(io::Error::from(kind));
+            }
+            Ok(self.data_to_read.as_bytes())
+        }
+
+        fn consume(&mut self, amt: usize) {
+            self.data_to_read.drain(..amt);
+        }
+    }
+
+    impl io::Write for ReadWriteMock {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if let Some(kind) = self.write_error {
+                return Err(io::Error::from(kind));
+            }
+            // lossy, same as the Display implementation of responses with binary content
+            self.written_data += &String::from_utf8_lossy(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.written_data_flushed += self.written_data.as_str();
+            self.written_data.clear();
+            Ok(())
+        }
+    }
+
+    impl Connection for ReadWriteMock {
+        fn shutdown_write(&self) -> io::Result<()> {
+            self.write_shut_down.set(true);
+            Ok(())
+        }
+
+        fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_handle_connection_empty_handles() -> io::Result<()> {
+        let ctx = ServerContext::default();
+        let mut stream = ReadWriteMock {
+            data_to_read: create_pattern(HTTPMethod::Get, ""),
+            ..Default::default()
+        };
+
+        handle_connection(&ctx, &mut stream, None)?;
+        assert_eq!("", stream.data_to_read);
+        assert_eq!("", stream.written_data);
+        assert_eq!(
+            stream.written_data_flushed,
+            format!(
+                "HTTP/1.1 404 Not Found\r\nContent-Length: {}\r\n\r\n{}",
+                HTTP_CONTENT_404.len(),
+                HTTP_CONTENT_404,
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_connection_empty_request() {
+        let server = HTTPServer::new();
+        let mut stream = ReadWriteMock::default();
+
+        let err = handle_connection(&server.ctx, &mut stream, None).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert_eq!("", stream.written_data_flushed);
+    }
+
+    #[test]
+    fn test_handle_connection_garbage_request() {
+        let mut server = HTTPServer::new();
+        server.add_handle(
+            HTTPMethod::Get,
+            "/",
+            Box::new(|_| Ok(HTTPResponse::new(200))),
+        );
+
+        for garbage in [
+            "\u{1}\u{2}\u{3}\r\n\r\n",
+            "Hello, world!\r\n\r\n",
+            "GET /\r\n\r\n",
+            "GET / FTP/1.1\r\n\r\n",
+            "GET  / HTTP/1.1\r\n\r\n",
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: String::from(garbage),
+                ..Default::default()
+            };
+            let err = handle_connection(&server.ctx, &mut stream, None).unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind(), "{:?}", garbage);
+            assert_eq!("", stream.written_data_flushed, "{:?}", garbage);
+        }
+
+        // a request line which isn't UTF-8
+        assert!(!has_request_line(b"GET /\xff HTTP/1.1\r\n\r\n"));
+        assert!(has_request_line(b"GET / HTTP/1.1\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_handle_connection_stalled_request() {
+        let server = HTTPServer::new();
+        let mut stream = ReadWriteMock {
+            read_error: Some(io::ErrorKind::WouldBlock),
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        let err = handle_connection(&server.ctx, &mut stream, None).unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!("", stream.written_data_flushed);
+    }
+
+    #[test]
+    fn test_handle_connection_lowercase_method() -> io::Result<()> {
+        let handles = RouteTable::default();
+        handles
+            .replace_routes(vec![(
+                HTTPMethod::Get,
+                "/Foo",
+                Box::new(|_| Ok(HTTPResponse::new(200).with_content("Foo, bar!"))),
+            )])
+            .unwrap();
+        let ctx = ServerContext {
+            handles,
+            ..Default::default()
+        };
+
+        for method in &["get", "Get", "GET"] {
+            let mut stream = ReadWriteMock {
+                data_to_read: format!("{} /Foo HTTP/1.1\r\n\r\n", method),
+                ..Default::default()
+            };
+            handle_connection(&ctx, &mut stream, None)?;
+            assert_eq!(
+                stream.written_data_flushed,
+                HTTPResponse::new(200).with_content("Foo, bar!").to_string(),
+            );
+        }
+
+        // the path keeps its case
+        let mut stream = ReadWriteMock {
+            data_to_read: String::from("get /foo HTTP/1.1\r\n\r\n"),
+            ..Default::default()
+        };
+        handle_connection(&ctx, &mut stream, None)?;
+        assert!(stream
+            .written_data_flushed
+            .starts_with("HTTP/1.1 404 Not Found\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_connection_with_handles() -> io::Result<()> {
+        let handles = RouteTable::default();
+        handles
+            .replace_routes(vec![
+                (
+                    HTTPMethod::Post,
+                    "/",
+                    Box::new(|_| Ok(HTTPResponse::new(200))),
+                ),
+                (
+                    HTTPMethod::Get,
+                    "/foo",
+                    Box::new(|_| Ok(HTTPResponse::new(200).with_content("Foo, bar!"))),
+                ),
+            ])
+            .unwrap();
+        let ctx = ServerContext {
+            handles,
+            ..Default::default()
+        };
+        let mut stream = ReadWriteMock {
+            data_to_read: create_pattern(HTTPMethod::Get, ""),
+            ..Default::default()
+        };
+
+        handle_connection(&ctx, &mut stream, None)?;
+        assert_eq!("", stream.data_to_read);
+        assert_eq!("", stream.written_data);
+        assert_eq!(
+            stream.written_data_flushed,
+            HTTPResponse::new(405)
+                .with_header("Allow", "POST")
+                .with_header("Content-Length", "0")
+                .to_string(),
+        );
+
+        stream.clear();
+        stream.data_to_read = create_pattern(HTTPMethod::Get, "/foo");
+
+        handle_connection(&ctx, &mut stream, None)?;
+        assert_eq!("", stream.data_to_read);
+        assert_eq!("", stream.written_data);
+        assert_eq!(
+            stream.written_data_flushed,
+            HTTPResponse::new(200).with_content("Foo, bar!").to_string(),
+        );
+
+        stream.clear();
+        stream.data_to_read = create_pattern(HTTPMethod::Post, "/");
+
+        handle_connection(&ctx, &mut stream, None)?;
+        assert_eq!("", stream.data_to_read);
+        assert_eq!("", stream.written_data);
+        assert_eq!(
+            stream.written_data_flushed,
+            HTTPResponse::new(200)
+                .with_header("Content-Length", "0")
+                .to_string(),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_response_writer() -> io::Result<()> {
+        let mut buffer = Vec::new();
+        let mut writer = ResponseWriter::new(&mut buffer);
+        writer.write_header("Content-Type", "text/plain")?;
+        writer.write_body_chunk(b"Hello")?;
+        assert!(writer.write_header("X-Late", "yes").is_err());
+        writer.write_body_chunk(b"")?;
+        writer.write_body_chunk(b", World!")?;
+        writer.finish()?;
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n8\r\n, World!\r\n0\r\n\r\n",
+            String::from_utf8_lossy(&buffer),
+        );
+
+        let mut buffer = Vec::new();
+        let mut writer = ResponseWriter::new(&mut buffer);
+        writer.write_status(204)?;
+        assert!(writer.write_status(200).is_err());
+        writer.finish()?;
+        assert_eq!(
+            "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n",
+            String::from_utf8_lossy(&buffer)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_connection_streaming() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_handle(
+            HTTPMethod::Get,
+            "/rows",
+            Box::new(|_| Ok(HTTPResponse::new(200))),
+        );
+        server.add_streaming_handle(
+            HTTPMethod::Get,
+            "/rows",
+            Box::new(|_, w| {
+                w.write_status(200)?;
+                w.write_header("Content-Type", "text/csv")?;
+                for row in 1..=3 {
+                    w.write_body_chunk(format!("row {}\n", row).as_bytes())?;
+                }
+                Ok(())
+            }),
+        );
+        let mut stream = ReadWriteMock {
+            data_to_read: create_pattern(HTTPMethod::Get, "/rows"),
+            ..Default::default()
+        };
+
+        handle_connection(&server.ctx, &mut stream, None)?;
+        assert_eq!("", stream.written_data);
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Type: text/csv\r\n\
+             Transfer-Encoding: chunked\r\n\r\n6\r\nrow 1\n\r\n6\r\nrow 2\n\r\n6\r\nrow 3\n\r\n0\r\n\r\n",
+            stream.written_data_flushed,
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_connection_request_headers() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_handle(
+            HTTPMethod::Get,
+            "/",
+            Box::new(|request| {
+                let agent = request.header("User-Agent").unwrap_or("stranger");
+                Ok(HTTPResponse::new(200).with_content(&format!("Hi, {}!", agent)))
+            }),
+        );
+        let mut stream = ReadWriteMock {
+            data_to_read: format!(
+                "{}Host: localhost\r\nuser-agent: curl\r\n\r\n",
+                create_pattern(HTTPMethod::Get, "/")
+            ),
+            ..Default::default()
+        };
+
+        handle_connection(&server.ctx, &mut stream, None)?;
+        assert_eq!(
+            stream.written_data_flushed,
+            HTTPResponse::new(200).with_content("Hi, curl!").to_string(),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_connection_stopping() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_handle(
+            HTTPMethod::Get,
+            "/",
+            Box::new(|_| Ok(HTTPResponse::new(200).with_content("Bye!"))),
+        );
+        server.ctx.stopping.store(true, Ordering::SeqCst);
+
+        let mut stream = ReadWriteMock {
+            data_to_read: String::from("GET / HTTP/1.1\r\nConnection: keep-alive\r\n\r\n"),
+            ..Default::default()
+        };
+        assert!(!handle_connection(&server.ctx, &mut stream, None)?);
+        assert_eq!(
+            stream.written_data_flushed,
+            HTTPResponse::new(200)
+                .with_content("Bye!")
+                .with_header("Connection", "close")
+                .to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_connection_request_body() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_handle(
+            HTTPMethod::Post,
+            "/echo",
+            Box::new(|request| {
+                Ok(HTTPResponse::new(200)
+                    .with_content(&String::from_utf8_lossy(request.body()))
+                    .with_content_type("application/json"))
+            }),
+        );
+
+        let body = r#"{"name": "edurust"}"#;
+        for (request, expected) in [
+            (
+                format!("Content-Length: {}\r\n\r\n{}", body.len(), body),
+                HTTPResponse::new(200)
+                    .with_content(body)
+                    .with_content_type("application/json"),
+            ),
+            // no Content-Length means no body
+            (
+                format!("\r\n{}", body),
+                HTTPResponse::new(200)
+                    .with_content("")
+                    .with_content_type("application/json"),
+            ),
+            // a body shorter than its Content-Length
+            (
+                format!("Content-Length: {}\r\n\r\n{}", body.len() + 1, body),
+                HTTPResponse::new(400).with_header("Connection", "close"),
+            ),
+            (
+                String::from("Content-Length: many\r\n\r\n"),
+                HTTPResponse::new(400).with_header("Connection", "close"),
+            ),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: format!("{}{}", create_pattern(HTTPMethod::Post, "/echo"), request),
+                ..Default::default()
+            };
+            handle_connection(&server.ctx, &mut stream, None)?;
+            assert_eq!(stream.written_data_flushed, expected.to_string());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_connection_max_body_size() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_handle(
+            HTTPMethod::Post,
+            "/echo",
+            Box::new(|request| {
+                Ok(HTTPResponse::new(200).with_content(&String::from_utf8_lossy(request.body())))
+            }),
+        );
+
+        for (max, request, expected) in [
+            (
+                None,
+                "Content-Length: 999999999999\r\n\r\n",
+                HTTPResponse::new(413).with_header("Connection", "close"),
+            ),
+            (
+                Some(4),
+                "Content-Length: 5\r\n\r\nHello",
+                HTTPResponse::new(413).with_header("Connection", "close"),
+            ),
+            (
+                Some(4),
+                "Content-Length: 4\r\n\r\nHell",
+                HTTPResponse::new(200).with_content("Hell"),
+            ),
+            (
+                Some(4),
+                "Content-Length: -1\r\n\r\n",
+                HTTPResponse::new(400).with_header("Connection", "close"),
+            ),
+            (
+                Some(4),
+                "Content-Length: 4 2\r\n\r\n",
+                HTTPResponse::new(400).with_header("Connection", "close"),
+            ),
+            (
+                Some(4),
+                "Content-Length: abc\r\n\r\n",
+                HTTPResponse::new(400).with_header("Connection", "close"),
+            ),
+        ] {
+            if let Some(max) = max {
+                server.set_max_body_size(max);
+            }
+            let mut stream = ReadWriteMock {
+                data_to_read: format!("{}{}", create_pattern(HTTPMethod::Post, "/echo"), request),
+                ..Default::default()
+            };
+            handle_connection(&server.ctx, &mut stream, None)?;
+            assert_eq!(stream.written_data_flushed, expected.to_string());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_connection_upload() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_upload_handle(
+            HTTPMethod::Post,
+            "/count",
+            Box::new(|_, body| {
+                let count = io::copy(body, &mut io::sink())?;
+                Ok(HTTPResponse::new(200).with_content(&count.to_string()))
+            }),
+        );
+        server.add_upload_handle(
+            HTTPMethod::Post,
+            "/ignore",
+            Box::new(|_, _| Ok(HTTPResponse::new(202))),
+        );
+
+        // the body isn't limited by the max body size, as it's never held as a whole
+        let len = DEFAULT_MAX_BODY_SIZE * 2;
+        let mut stream = ReadWriteMock {
+            data_to_read: format!(
+                "{}Content-Length: {}\r\n\r\n{}GET / HTTP/1.1\r\n\r\n",
+                create_pattern(HTTPMethod::Post, "/count"),
+                len,
+                "a".repeat(len),
+            ),
+            ..Default::default()
+        };
+        assert!(handle_connection(&server.ctx, &mut stream, None)?);
+        assert_eq!(
+            stream.written_data_flushed,
+            HTTPResponse::new(200)
+                .with_content(&len.to_string())
+                .to_string()
+        );
+        assert_eq!(stream.data_to_read, "GET / HTTP/1.1\r\n\r\n");
+
+        // the connection is closed if the body isn't read entirely
+        let mut stream = ReadWriteMock {
+            data_to_read: format!(
+                "{}Content-Length: 5\r\n\r\nHello",
+                create_pattern(HTTPMethod::Post, "/ignore"),
+            ),
+            ..Default::default()
+        };
+        assert!(!handle_connection(&server.ctx, &mut stream, None)?);
+        assert_eq!(
+            stream.written_data_flushed,
+            HTTPResponse::new(202)
+                .with_header("Connection", "close")
+                .to_string()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_connection_expect_continue() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.set_max_body_size(4);
+        server.add_handle(
+            HTTPMethod::Post,
+            "/echo",
+            Box::new(|request| {
+                Ok(HTTPResponse::new(200).with_content(&String::from_utf8_lossy(request.body())))
+            }),
+        );
+
+        for (request, expected) in [
+            (
+                "Expect: 100-continue\r\nContent-Length: 4\r\n\r\nHell",
+                format!(
+                    "HTTP/1.1 100 Continue\r\n\r\n{}",
+                    HTTPResponse::new(200).with_content("Hell")
+                ),
+            ),
+            // rejected before the client sends its body
+            (
+                "Expect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+                HTTPResponse::new(417)
+                    .with_header("Connection", "close")
+                    .to_string(),
+            ),
+            // nothing to continue with without a body
+            (
+                "Expect: 100-continue\r\n\r\n",
+                HTTPResponse::new(200).with_content("").to_string(),
+            ),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: format!("{}{}", create_pattern(HTTPMethod::Post, "/echo"), request),
+                ..Default::default()
+            };
+            handle_connection(&server.ctx, &mut stream, None)?;
+            assert_eq!(stream.written_data_flushed, expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = parse_query("q=hello%20world&page=2");
+        assert_eq!(2, query.len());
+        assert_eq!("hello world", query["q"]);
+        assert_eq!("2", query["page"]);
+
+        let query = parse_query("q=a+b%2Bc&q=last&flag&bad=%zz%4");
+        assert_eq!("last", query["q"]);
+        assert_eq!("", query["flag"]);
+        assert_eq!("%zz%4", query["bad"]);
+
+        // only two hex digits are decoded
+        let query = parse_query("sign=%+A&neg=%-1&hex=%4a%4A");
+        assert_eq!("% A", query["sign"]);
+        assert_eq!("%-1", query["neg"]);
+        assert_eq!("JJ", query["hex"]);
+
+        assert!(parse_query("").is_empty());
+    }
+
+    #[test]
+    fn test_handle_connection_query() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_handle(
+            HTTPMethod::Get,
+            "/search",
+            Box::new(|request| {
+                let mut params: Vec<_> = request.query().iter().collect();
+                params.sort();
+                Ok(HTTPResponse::new(200).with_content(&format!("{:?}", params)))
+            }),
+        );
+
+        for (path, expected) in [
+            (
+                "/search?q=hello%20world&page=2",
+                r#"[("page", "2"), ("q", "hello world")]"#,
+            ),
+            ("/search?", "[]"),
+            ("/search", "[]"),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: create_pattern(HTTPMethod::Get, path),
+                ..Default::default()
+            };
+            handle_connection(&server.ctx, &mut stream, None)?;
+            assert_eq!(
+                stream.written_data_flushed,
+                HTTPResponse::new(200).with_content(expected).to_string(),
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_match_path() {
+        let params = match_path("/users/:id", "/users/42").unwrap();
+        assert_eq!(1, params.len());
+        assert_eq!("42", params["id"]);
+
+        let params = match_path("/users/:id/posts/:post", "/users/a%20b/posts/7").unwrap();
+        assert_eq!("a b", params["id"]);
+        assert_eq!("7", params["post"]);
+
+        assert!(match_path("/users/:id", "/users/42/extra").is_none());
+        assert!(match_path("/users/:id", "/users/").is_none());
+        assert!(match_path("/users/:id", "/users").is_none());
+        assert!(match_path("/users/:id", "/groups/42").is_none());
+
+        let params = match_path("/static/*", "/static/css/app.css").unwrap();
+        assert_eq!("css/app.css", params["*"]);
+        assert_eq!("", match_path("/static/*", "/static/").unwrap()["*"]);
+        assert!(match_path("/static/*", "/static").is_none());
+        let params = match_path("/users/:id/*", "/users/42/posts/7").unwrap();
+        assert_eq!("42", params["id"]);
+        assert_eq!("posts/7", params["*"]);
+    }
+
+    #[test]
+    fn test_handle_connection_wildcard() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_handle(
+            HTTPMethod::Get,
+            "/static/*",
+            Box::new(|request| {
+                let file = request.param("*").unwrap();
+                Ok(HTTPResponse::new(200).with_content(&format!("file {}", file)))
+            }),
+        );
+        server.add_handle(
+            HTTPMethod::Get,
+            "/static/logo.png",
+            Box::new(|_| Ok(HTTPResponse::new(200).with_content("logo"))),
+        );
+        server.add_handle(
+            HTTPMethod::Get,
+            "/static/:file",
+            Box::new(|_| Ok(HTTPResponse::new(200).with_content("param"))),
+        );
+
+        for (path, expected) in [
+            ("/static/css/app.css", "file css/app.css"),
+            ("/static/js/main.js", "file js/main.js"),
+            // exact paths take precedence, followed by paths with parameters
+            ("/static/logo.png", "logo"),
+            ("/static/favicon.ico", "param"),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: create_pattern(HTTPMethod::Get, path),
+                ..Default::default()
+            };
+            handle_connection(&server.ctx, &mut stream, None)?;
+            assert_eq!(
+                stream.written_data_flushed,
+                HTTPResponse::new(200).with_content(expected).to_string()
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_connection_path_params() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_handle(
+            HTTPMethod::Get,
+            "/users/:id",
+            Box::new(|request| {
+                let id = request.param("id").unwrap();
+                Ok(HTTPResponse::new(200).with_content(&format!("user {}", id)))
+            }),
+        );
+        server.add_handle(
+            HTTPMethod::Get,
+            "/users/me",
+            Box::new(|_| Ok(HTTPResponse::new(200).with_content("me"))),
+        );
+        server.add_handle(
+            HTTPMethod::Get,
+            "/:kind/:id",
+            Box::new(|_| Ok(HTTPResponse::new(200).with_content("anything"))),
+        );
+
+        for (path, expected) in [
+            ("/users/42", HTTPResponse::new(200).with_content("user 42")),
+            (
+                "/users/42?page=2",
+                HTTPResponse::new(200).with_content("user 42"),
+            ),
+            // exact paths take precedence
+            ("/users/me", HTTPResponse::new(200).with_content("me")),
+            (
+                "/groups/42",
+                HTTPResponse::new(200).with_content("anything"),
+            ),
+            (
+                "/users/42/extra",
+                HTTPResponse::new(404).with_content(HTTP_CONTENT_404),
+            ),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: create_pattern(HTTPMethod::Get, path),
+                ..Default::default()
+            };
+            handle_connection(&server.ctx, &mut stream, None)?;
+            assert_eq!(stream.written_data_flushed, expected.to_string());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve_dir() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("webservice-serve-dir-{}", std::process::id()));
+        let root = dir.join("public");
+        fs::create_dir_all(root.join("css"))?;
+        fs::write(root.join("index.html"), "<h1>Hi!</h1>")?;
+        fs::write(root.join("css").join("app.css"), "h1 { color: red; }")?;
+        fs::write(root.join("logo.png"), [0x89, b'P', b'N', b'G', 0xff])?;
+        fs::write(dir.join("secret.txt"), "secret")?;
+
+        let mut server = HTTPServer::new();
+        server.serve_dir("/static/", &root);
+
+        for (path, expected) in [
+            (
+                "/static/index.html",
+                HTTPResponse::new(200)
+                    .with_content("<h1>Hi!</h1>")
+                    .with_content_type("text/html"),
+            ),
+            (
+                "/static/css/app.css",
+                HTTPResponse::new(200)
+                    .with_content("h1 { color: red; }")
+                    .with_content_type("text/css"),
+            ),
+            (
+                "/static/logo.png",
+                HTTPResponse::new(200)
+                    .with_bytes(vec![0x89, b'P', b'N', b'G', 0xff])
+                    .with_content_type("image/png"),
+            ),
+            (
+                "/static/",
+                HTTPResponse::new(200)
+                    .with_content("<h1>Hi!</h1>")
+                    .with_content_type("text/html"),
+            ),
+            ("/static/missing.html", not_found()),
+            // a directory without an index.html
+            ("/static/css", not_found()),
+            (
+                "/static/../secret.txt",
+                HTTPResponse::new(403).with_header("Content-Length", "0"),
+            ),
+            (
+                "/static/css/%2e%2e/%2e%2e/secret.txt",
+                HTTPResponse::new(403).with_header("Content-Length", "0"),
+            ),
+            (
+                "/static//etc/passwd",
+                HTTPResponse::new(403).with_header("Content-Length", "0"),
+            ),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: create_pattern(HTTPMethod::Get, path),
+                ..Default::default()
+            };
+            handle_connection(&server.ctx, &mut stream, None)?;
+            assert_eq!(
+                stream.written_data_flushed,
+                expected.to_string(),
+                "{}",
+                path
+            );
+        }
+
+        fs::remove_dir_all(dir)
+    }
+
+    #[test]
+    fn test_handle_connection_exact_match() -> io::Result<()> {
+        let routes = || -> Vec<(HTTPMethod, &str, HTTPHandle)> {
+            vec![
+                (
+                    HTTPMethod::Get,
+                    "/foo",
+                    Box::new(|_| Ok(HTTPResponse::new(200).with_content("foo"))),
+                ),
+                (
+                    HTTPMethod::Get,
+                    "/foo/bar",
+                    Box::new(|_| Ok(HTTPResponse::new(200).with_content("bar"))),
+                ),
+            ]
+        };
+
+        for reversed in [false, true] {
+            let mut routes = routes();
+            if reversed {
+                routes.reverse();
+            }
+            let mut server = HTTPServer::new();
+            server.add_routes(routes);
+
+            for (request, expected) in [
+                (
+                    "GET /foo HTTP/1.1\r\n\r\n",
+                    HTTPResponse::new(200).with_content("foo"),
+                ),
+                (
+                    "GET /foo/bar HTTP/1.1\r\n\r\n",
+                    HTTPResponse::new(200).with_content("bar"),
+                ),
+                (
+                    "GET /foo/baz HTTP/1.1\r\n\r\n",
+                    HTTPResponse::new(404).with_content(HTTP_CONTENT_404),
+                ),
+                (
+                    "GET /foo HTTP/1.1",
+                    HTTPResponse::new(404).with_content(HTTP_CONTENT_404),
+                ),
+            ] {
+                let mut stream = ReadWriteMock {
+                    data_to_read: String::from(request),
+                    ..Default::default()
+                };
+                handle_connection(&server.ctx, &mut stream, None)?;
+                assert_eq!(
+                    stream.written_data_flushed,
+                    expected.to_string(),
+                    "{:?}",
+                    request
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_route_latencies() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        let clock = FakeClock::new();
+        server.set_clock(clock.clone());
+        let requests = AtomicU64::new(0);
+        server.add_handle(
+            HTTPMethod::Get,
+            "/slow",
+            Box::new(move |_| {
+                // 1ms up to 10ms
+                let n = requests.fetch_add(1, Ordering::SeqCst) + 1;
+                clock.advance(Duration::from_millis(n));
+                Ok(HTTPResponse::new(200))
+            }),
+        );
+        server.add_handle(
+            HTTPMethod::Get,
+            "/unused",
+            Box::new(|_| Ok(HTTPResponse::new(200))),
+        );
+        assert!(server.latency_tracker().is_none());
+        server.enable_latency_tracking();
+
+        for path in ["/slow"; 10].iter().chain(&["/missing"]) {
+            let mut stream = ReadWriteMock {
+                data_to_read: create_pattern(HTTPMethod::Get, path),
+                ..Default::default()
+            };
+            handle_connection(&server.ctx, &mut stream, None)?;
+        }
+
+        let latencies = server.latency_tracker().unwrap().route_latencies();
+        assert_eq!(vec!["GET /slow"], latencies.keys().collect::<Vec<_>>());
+        let summary = latencies["GET /slow"];
+        assert_eq!(10, summary.count);
+        assert_eq!(Duration::from_millis(5), summary.p50);
+        assert_eq!(Duration::from_millis(9), summary.p90);
+        assert_eq!(Duration::from_millis(10), summary.p99);
+
+        // recorded for the route, no matter the HTTP version of the request
+        let mut stream = ReadWriteMock {
+            data_to_read: String::from("GET /slow HTTP/1.0\r\n\r\n"),
+            ..Default::default()
+        };
+        handle_connection(&server.ctx, &mut stream, None)?;
+        let latencies = server.latency_tracker().unwrap().route_latencies();
+        assert_eq!(vec!["GET /slow"], latencies.keys().collect::<Vec<_>>());
+        assert_eq!(11, latencies["GET /slow"].count);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_connection_client_disconnect() {
+        let server = HTTPServer::new();
+        for (kind, disconnect) in [
+            (io::ErrorKind::BrokenPipe, true),
+            (io::ErrorKind::ConnectionReset, true),
+            (io::ErrorKind::Other, false),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: create_pattern(HTTPMethod::Get, "/"),
+                write_error: Some(kind),
+                ..Default::default()
+            };
+            let err = handle_connection(&server.ctx, &mut stream, None).unwrap_err();
+            assert_eq!(kind, err.kind());
+            assert_eq!(disconnect, is_client_disconnect(&err));
+        }
+    }
+
+    #[test]
+    fn test_handle_connection_head() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_handle(
+            HTTPMethod::Get,
+            "/",
+            Box::new(|_| Ok(HTTPResponse::new(200).with_content("hello"))),
+        );
+        server.add_handle(
+            HTTPMethod::Get,
+            "/foo",
+            Box::new(|_| Ok(HTTPResponse::new(200).with_content("foo"))),
+        );
+        server.add_handle(
+            HTTPMethod::Head,
+            "/foo",
+            Box::new(|_| Ok(HTTPResponse::new(204))),
+        );
+
+        for (path, expected) in [
+            // the GET handle is used, but without the body
+            (
+                "/",
+                "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n".to_owned(),
+            ),
+            // unless a HEAD handle was added
+            ("/foo", "HTTP/1.1 204 No Content\r\n\r\n".to_owned()),
+            (
+                "/bar",
+                format!(
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: {}\r\n\r\n",
+                    HTTP_CONTENT_404.len()
+                ),
+            ),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: create_pattern(HTTPMethod::Head, path),
+                ..Default::default()
+            };
+            handle_connection(&server.ctx, &mut stream, None)?;
+            assert_eq!(expected, stream.written_data_flushed);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_connection_handle_error() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_handle(
+            HTTPMethod::Get,
+            "/",
+            Box::new(|_| Err(io::Error::new(io::ErrorKind::NotFound, "hello.html"))),
+        );
+        server.add_streaming_handle(
+            HTTPMethod::Get,
+            "/stream",
+            Box::new(|_, _| Err(io::Error::new(io::ErrorKind::NotFound, "rows.csv"))),
+        );
+        server.add_streaming_handle(
+            HTTPMethod::Get,
+            "/broken",
+            Box::new(|_, w| {
+                w.write_body_chunk(b"row 1")?;
+                Err(io::Error::new(io::ErrorKind::NotFound, "rows.csv"))
+            }),
+        );
+
+        for (path, expected) in [
+            ("/", internal_server_error()),
+            // a streaming handle fails after the connection is known to be closed
+            (
+                "/stream",
+                internal_server_error().with_header("Connection", "close"),
+            ),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: create_pattern(HTTPMethod::Get, path),
+                ..Default::default()
+            };
+            handle_connection(&server.ctx, &mut stream, None)?;
+            assert!(stream
+                .written_data_flushed
+                .starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+            assert_eq!(stream.written_data_flushed, expected.to_string());
+        }
+
+        // too late to respond with a 500 once part of the response was written
+        let mut stream = ReadWriteMock {
+            data_to_read: create_pattern(HTTPMethod::Get, "/broken"),
+            ..Default::default()
+        };
+        assert!(handle_connection(&server.ctx, &mut stream, None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_routes() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_routes(vec![
+            (
+                HTTPMethod::Get,
+                "/",
+                Box::new(|_| Ok(HTTPResponse::new(200).with_content("root"))),
+            ),
+            (
+                HTTPMethod::Get,
+                "/foo",
+                Box::new(|_| Ok(HTTPResponse::new(200).with_content("foo"))),
+            ),
+            (
+                HTTPMethod::Post,
+                "/foo",
+                Box::new(|_| Ok(HTTPResponse::new(201))),
+            ),
+        ]);
+        let ctx = server.ctx;
+
+        for (pattern, expected) in [
+            (
+                create_pattern(HTTPMethod::Get, "/"),
+                HTTPResponse::new(200).with_content("root"),
+            ),
+            (
+                create_pattern(HTTPMethod::Get, "/foo"),
+                HTTPResponse::new(200).with_content("foo"),
+            ),
+            (
+                create_pattern(HTTPMethod::Post, "/foo"),
+                HTTPResponse::new(201).with_header("Content-Length", "0"),
+            ),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: pattern,
+                ..Default::default()
+            };
+            handle_connection(&ctx, &mut stream, None)?;
+            assert_eq!(stream.written_data_flushed, expected.to_string());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_content_type() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.set_default_content_type("text/plain; charset=utf-8");
+        server.add_routes(vec![
+            (
+                HTTPMethod::Get,
+                "/text",
+                Box::new(|_| Ok(HTTPResponse::new(200).with_content("Hello!"))),
+            ),
+            (
+                HTTPMethod::Get,
+                "/html",
+                Box::new(|_| {
+                    Ok(HTTPResponse::new(200)
+                        .with_content("<p>Hello!</p>")
+                        .with_content_type("text/html"))
+                }),
+            ),
+            (
+                HTTPMethod::Get,
+                "/empty",
+                Box::new(|_| Ok(HTTPResponse::new(204))),
+            ),
+        ]);
+        let ctx = server.ctx;
+
+        for (pattern, expected) in [
+            (
+                create_pattern(HTTPMethod::Get, "/text"),
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 6\r\n\r\nHello!",
+            ),
+            (
+                create_pattern(HTTPMethod::Get, "/html"),
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 13\r\n\r\n<p>Hello!</p>",
+            ),
+            (
+                create_pattern(HTTPMethod::Get, "/empty"),
+                "HTTP/1.1 204 No Content\r\n\r\n",
+            ),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: pattern,
+                ..Default::default()
+            };
+            handle_connection(&ctx, &mut stream, None)?;
+            assert_eq!(stream.written_data_flushed, expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_200_as_204() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_routes(vec![
+            (
+                HTTPMethod::Post,
+                "/empty",
+                Box::new(|_| Ok(HTTPResponse::new(200))),
+            ),
+            (
+                HTTPMethod::Post,
+                "/content",
+                Box::new(|_| Ok(HTTPResponse::new(200).with_content("Done!"))),
+            ),
+            (
+                HTTPMethod::Post,
+                "/created",
+                Box::new(|_| Ok(HTTPResponse::new(201))),
+            ),
+        ]);
+
+        for (enabled, path, expected) in [
+            (
+                false,
+                "/empty",
+                HTTPResponse::new(200).with_header("Content-Length", "0"),
+            ),
+            (true, "/empty", HTTPResponse::new(204)),
+            (
+                true,
+                "/content",
+                HTTPResponse::new(200).with_content("Done!"),
+            ),
+            (
+                true,
+                "/created",
+                HTTPResponse::new(201).with_header("Content-Length", "0"),
+            ),
+        ] {
+            server.set_empty_200_as_204(enabled);
+            let mut stream = ReadWriteMock {
+                data_to_read: create_pattern(HTTPMethod::Post, path),
+                ..Default::default()
+            };
+            handle_connection(&server.ctx, &mut stream, None)?;
+            assert_eq!(stream.written_data_flushed, expected.to_string());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_method_not_allowed() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_routes(vec![
+            (
+                HTTPMethod::Get,
+                "/foo",
+                Box::new(|_| Ok(HTTPResponse::new(200).with_content("foo"))),
+            ),
+            (
+                HTTPMethod::Delete,
+                "/users/:id",
+                Box::new(|_| Ok(HTTPResponse::new(204))),
+            ),
+        ]);
+
+        for (method, path, expected) in [
+            (
+                HTTPMethod::Post,
+                "/foo",
+                HTTPResponse::new(405)
+                    .with_header("Allow", "GET, HEAD")
+                    .with_header("Content-Length", "0"),
+            ),
+            (
+                HTTPMethod::Get,
+                "/users/42",
+                HTTPResponse::new(405)
+                    .with_header("Allow", "DELETE")
+                    .with_header("Content-Length", "0"),
+            ),
+            (
+                HTTPMethod::Post,
+                "/bar",
+                HTTPResponse::new(404).with_content(HTTP_CONTENT_404),
+            ),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: create_pattern(method, path),
+                ..Default::default()
+            };
+            handle_connection(&server.ctx, &mut stream, None)?;
+            assert_eq!(stream.written_data_flushed, expected.to_string());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_not_found_handler() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_handle(
+            HTTPMethod::Get,
+            "/",
+            Box::new(|_| Ok(HTTPResponse::new(200).with_content("Hi!"))),
+        );
+        server.set_not_found_handler(Box::new(|request| {
+            Ok(match request.query().get("q") {
+                Some(q) => HTTPResponse::new(302).with_header("Location", &format!("/?q={}", q)),
+                None => HTTPResponse::new(404).with_content("Nothing to see here."),
+            })
+        }));
+
+        for (path, expected) in [
+            ("/", HTTPResponse::new(200).with_content("Hi!")),
+            (
+                "/foo",
+                HTTPResponse::new(404).with_content("Nothing to see here."),
+            ),
+            (
+                "/foo?q=bar",
+                HTTPResponse::new(302)
+                    .with_header("Location", "/?q=bar")
+                    .with_header("Content-Length", "0"),
+            ),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: create_pattern(HTTPMethod::Get, path),
+                ..Default::default()
+            };
+            handle_connection(&server.ctx, &mut stream, None)?;
+            assert_eq!(stream.written_data_flushed, expected.to_string());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_handle_connection_body_stream() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_handle(
+            HTTPMethod::Get,
+            "/stream",
+            Box::new(|_| {
+                let chunks: Vec<io::Result<Vec<u8>>> = ["Hello", "", ", ", "world!"]
+                    .iter()
+                    .map(|c| Ok(c.as_bytes().to_vec()))
+                    .collect();
+                Ok(HTTPResponse::new(200)
+                    .with_content_type("text/plain")
+                    .with_stream(Box::new(chunks.into_iter())))
+            }),
+        );
+
+        let head =
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n";
+        for (method, body) in [
+            (
+                HTTPMethod::Get,
+                "5\r\nHello\r\n2\r\n, \r\n6\r\nworld!\r\n0\r\n\r\n",
+            ),
+            (HTTPMethod::Head, ""),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: create_pattern(method, "/stream"),
+                ..Default::default()
+            };
+            handle_connection(&server.ctx, &mut stream, None)?;
+            assert_eq!(stream.written_data_flushed, format!("{}{}", head, body));
+        }
+
+        // the chunks reassemble to the streamed body, with nothing following the last one
+        let mut stream = ReadWriteMock {
+            data_to_read: create_pattern(HTTPMethod::Get, "/stream"),
+            ..Default::default()
+        };
+        handle_connection(&server.ctx, &mut stream, None)?;
+        let mut rest = stream.written_data_flushed.strip_prefix(head).unwrap();
+        let mut body = String::new();
+        loop {
+            let (len, chunk) = rest.split_once("\r\n").unwrap();
+            let len = usize::from_str_radix(len, 16).unwrap();
+            if len == 0 {
+                assert_eq!("\r\n", chunk);
+                break;
+            }
+            body += &chunk[..len];
+            rest = &chunk[len + 2..];
+        }
+        assert_eq!("Hello, world!", body);
+
+        // HTTP/1.0 clients get the body as it is, ending once the connection is closed
+        let mut stream = ReadWriteMock {
+            data_to_read: String::from("GET /stream HTTP/1.0\r\nConnection: keep-alive\r\n\r\n"),
+            ..Default::default()
+        };
+        assert!(!handle_connection(&server.ctx, &mut stream, None)?);
+        assert_eq!(
+            stream.written_data_flushed,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\nHello, world!"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_http_response_with_trailer() {
+        let chunks: Vec<io::Result<Vec<u8>>> = vec![Ok(b"Hello".to_vec())];
+        let response = HTTPResponse::new(200)
+            .with_stream(Box::new(chunks.into_iter()))
+            .with_trailer("Content-MD5", "8b1a9953c4611296a827abf8c47804d7")
+            .with_trailer("X-Rows", "1");
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nTrailer: Content-MD5, X-Rows\r\n\r\n\
+             5\r\nHello\r\n0\r\nContent-MD5: 8b1a9953c4611296a827abf8c47804d7\r\nX-Rows: 1\r\n\r\n",
+            response.to_string(),
+        );
+
+        // left out of a response which isn't streamed
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nHello",
+            HTTPResponse::new(200)
+                .with_content("Hello")
+                .with_trailer("X-Rows", "1")
+                .to_string(),
+        );
+    }
+
+    #[test]
+    fn test_middlewares() -> io::Result<()> {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut server = HTTPServer::new();
+        server.add_handle(
+            HTTPMethod::Get,
+            "/",
+            Box::new(|_| Ok(HTTPResponse::new(200).with_content("Hi!"))),
+        );
+        server.add_handle(
+            HTTPMethod::Post,
+            "/fail",
+            Box::new(|_| Err(io::Error::other("oops"))),
+        );
+        for name in ["first", "second"] {
+            let log = Arc::clone(&log);
+            server.add_middleware(Box::new(move |info, response| {
+                log.lock().unwrap().push(format!(
+                    "{} {} {} {}",
+                    name, info.method, info.path, response.status
+                ));
+            }));
+        }
+
+        for (method, path) in [
+            (HTTPMethod::Get, "/?name=foo"),
+            (HTTPMethod::Get, "/foo"),
+            (HTTPMethod::Post, "/fail"),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: create_pattern(method, path),
+                ..Default::default()
+            };
+            handle_connection(&server.ctx, &mut stream, None)?;
+        }
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "first GET / 200",
+                "second GET / 200",
+                "first GET /foo 404",
+                "second GET /foo 404",
+                "first POST /fail 500",
+                "second POST /fail 500",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_response_transformers() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_handle(
+            HTTPMethod::Get,
+            "/",
+            Box::new(|_| Ok(HTTPResponse::new(200).with_content("Hi!"))),
+        );
+        server.add_response_transformer(Box::new(|response| {
+            response.set_header("X-Frame-Options", "SAMEORIGIN");
+        }));
+        server.add_response_transformer(Box::new(|response| {
+            response.set_header("X-Content-Type-Options", "nosniff");
+            response.set_header("X-Frame-Options", "DENY");
+        }));
+        let ctx = server.ctx;
+
+        for (path, expected) in [
+            ("/", HTTPResponse::new(200).with_content("Hi!")),
+            (
+                "/foo",
+                HTTPResponse::new(404).with_content(HTTP_CONTENT_404),
+            ),
+        ] {
+            let mut expected = expected;
+            expected.set_header("X-Frame-Options", "DENY");
+            expected.set_header("X-Content-Type-Options", "nosniff");
+            let mut stream = ReadWriteMock {
+                data_to_read: create_pattern(HTTPMethod::Get, path),
+                ..Default::default()
+            };
+            handle_connection(&ctx, &mut stream, None)?;
+            assert_eq!(stream.written_data_flushed, expected.to_string());
+            assert!(stream
+                .written_data_flushed
+                .contains("\r\nX-Frame-Options: DENY\r\nX-Content-Type-Options: nosniff\r\n"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_global_headers() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.add_handle(
+            HTTPMethod::Get,
+            "/",
+            Box::new(|_| Ok(HTTPResponse::new(200).with_content("Hi!"))),
+        );
+        server.add_handle(
+            HTTPMethod::Get,
+            "/h3",
+            Box::new(|_| {
+                Ok(HTTPResponse::new(200)
+                    .with_content("Hi!")
+                    .with_header("alt-svc", "h3=\":443\""))
+            }),
+        );
+        server.add_global_header("Alt-Svc", "clear");
+        server.add_global_header("X-Content-Type-Options", "nosniff");
+
+        for (path, expected) in [
+            (
+                "/",
+                HTTPResponse::new(200)
+                    .with_content("Hi!")
+                    .with_header("Alt-Svc", "clear")
+                    .with_header("X-Content-Type-Options", "nosniff"),
+            ),
+            (
+                "/h3",
+                HTTPResponse::new(200)
+                    .with_content("Hi!")
+                    .with_header("alt-svc", "h3=\":443\"")
+                    .with_header("X-Content-Type-Options", "nosniff"),
+            ),
+            (
+                "/foo",
+                HTTPResponse::new(404)
+                    .with_content(HTTP_CONTENT_404)
+                    .with_header("Alt-Svc", "clear")
+                    .with_header("X-Content-Type-Options", "nosniff"),
+            ),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: create_pattern(HTTPMethod::Get, path),
+                ..Default::default()
+            };
+            handle_connection(&server.ctx, &mut stream, None)?;
+            assert_eq!(stream.written_data_flushed, expected.to_string());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_request_line() -> io::Result<()> {
+        let path = format!("/{}", "a".repeat(2000));
+        let mut server = HTTPServer::new();
+        server.add_handle(
+            HTTPMethod::Get,
+            &path,
+            Box::new(|_| Ok(HTTPResponse::new(200).with_content("long"))),
+        );
+
+        let mut stream = ReadWriteMock {
+            data_to_read: format!(
+                "{}Host: localhost\r\n\r\n",
+                create_pattern(HTTPMethod::Get, &path)
+            ),
+            ..Default::default()
+        };
+        handle_connection(&server.ctx, &mut stream, None)?;
+        assert_eq!(
+            stream.written_data_flushed,
+            HTTPResponse::new(200).with_content("long").to_string(),
+        );
+
+        server.set_max_request_line_bytes(1500);
+        for request in [
+            create_pattern(HTTPMethod::Get, &path),
+            format!("GET {}", path),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: request,
+                ..Default::default()
+            };
+            handle_connection(&server.ctx, &mut stream, None)?;
+            assert_eq!(
+                stream.written_data_flushed,
+                HTTPResponse::new(431)
+                    .with_header("Connection", "close")
+                    .to_string()
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_header_line_bytes() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.set_max_header_line_bytes(64);
+        server.add_handle(
+            HTTPMethod::Get,
+            "/",
+            Box::new(|_| Ok(HTTPResponse::new(200))),
+        );
+        let ctx = server.ctx;
+
+        for (cookie, expected) in [
+            (
+                "a".repeat(56),
+                "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n",
+            ),
+            (
+                "a".repeat(57),
+                "HTTP/1.1 431 Request Header Fields Too Large\r\nConnection: close\r\n\r\n",
+            ),
+            (
+                "a".repeat(1024),
+                "HTTP/1.1 431 Request Header Fields Too Large\r\nConnection: close\r\n\r\n",
+            ),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: format!(
+                    "{}Host: localhost\r\nCookie: {}\r\n\r\n",
+                    create_pattern(HTTPMethod::Get, "/"),
+                    cookie,
+                ),
+                ..Default::default()
+            };
+            handle_connection(&ctx, &mut stream, None)?;
+            assert_eq!(stream.written_data_flushed, expected);
+        }
+
+        // a client which never completes its head
+        struct Stalled;
+        impl Read for Stalled {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::from(io::ErrorKind::TimedOut))
+            }
+        }
+        let head = |cookie: usize| {
+            let head = format!(
+                "{}Cookie: {}",
+                create_pattern(HTTPMethod::Get, "/"),
+                "a".repeat(cookie)
+            );
+            io::BufReader::with_capacity(16, io::Cursor::new(head).chain(Stalled))
+        };
+        // rejected as soon as the header line being received is too long
+        assert_eq!(
+            None,
+            read_request_head(&mut head(57), 8192, Some(64), 65536)?
+        );
+        for (cookie, max) in [(56, Some(64)), (1024, None)] {
+            let err = read_request_head(&mut head(cookie), 8192, max, 65536).unwrap_err();
+            assert_eq!(io::ErrorKind::TimedOut, err.kind());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_head_bytes() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.set_max_head_bytes(256);
+        server.add_handle(
+            HTTPMethod::Get,
+            "/",
+            Box::new(|_| Ok(HTTPResponse::new(200))),
+        );
+        let ctx = server.ctx;
+
+        for (headers, expected) in [
+            (4, "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"),
+            (
+                16,
+                "HTTP/1.1 431 Request Header Fields Too Large\r\nConnection: close\r\n\r\n",
+            ),
+        ] {
+            let mut stream = ReadWriteMock {
+                data_to_read: format!(
+                    "{}{}\r\n",
+                    create_pattern(HTTPMethod::Get, "/"),
+                    "X-Padding: aaaaaaaaaaaaaaaa\r\n".repeat(headers),
+                ),
+                ..Default::default()
+            };
+            handle_connection(&ctx, &mut stream, None)?;
+            assert_eq!(stream.written_data_flushed, expected, "{}", headers);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_head_received_in_parts() -> io::Result<()> {
+        let (port, tx, server) = spawn_server(|server| {
+            server.add_handle(
+                HTTPMethod::Get,
+                "/",
+                Box::new(|request| {
+                    let host = request.header("Host").unwrap_or("none");
+                    Ok(HTTPResponse::new(200).with_content(host))
+                }),
+            );
+        })?;
+
+        let mut stream = connect(port)?;
+        stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n")?;
+        stream.flush()?;
+        std::thread::sleep(Duration::from_millis(100));
+        stream.write_all(b"Host: example\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        // the headers are only parsed once received, and not read as a next request
+        assert_eq!(
+            response,
+            HTTPResponse::new(200)
+                .with_content("example")
+                .with_header("Connection", "close")
+                .to_string(),
+        );
+
+        tx.send(()).unwrap();
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_max_send_rate() -> io::Result<()> {
+        let body = "a".repeat(1000);
+        let mut server = HTTPServer::new();
+        server.set_max_send_rate(4000);
+        let content = body.clone();
+        server.add_handle(
+            HTTPMethod::Get,
+            "/",
+            Box::new(move |_| Ok(HTTPResponse::new(200).with_content(&content))),
+        );
+        let mut stream = ReadWriteMock {
+            data_to_read: create_pattern(HTTPMethod::Get, "/"),
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        handle_connection(&server.ctx, &mut stream, None)?;
+        // 1000 bytes at 4000 bytes per second
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert_eq!(
+            stream.written_data_flushed,
+            HTTPResponse::new(200).with_content(&body).to_string(),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_options() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let _client = TcpStream::connect(listener.local_addr()?)?;
+        let (stream, _) = listener.accept()?;
+
+        let mut server = HTTPServer::new();
+        server.stream_options.apply(&stream)?;
+        assert_eq!(None, SockRef::from(&stream).linger()?);
+
+        server.set_linger(Some(Duration::from_secs(1)));
+        server.set_recv_buffer_size(32 * 1024);
+        server.set_send_buffer_size(16 * 1024);
+        server.stream_options.apply(&stream)?;
+        let socket = SockRef::from(&stream);
+        assert_eq!(Some(Duration::from_secs(1)), socket.linger()?);
+        assert!(socket.recv_buffer_size()? >= 32 * 1024);
+        assert!(socket.send_buffer_size()? >= 16 * 1024);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_listen_with_buffer_sizes() -> io::Result<()> {
+        let content = "Hello! ".repeat(4096);
+        let expected = HTTPResponse::new(200)
+            .with_header("Connection", "close")
+            .with_content(&content)
+            .to_string();
+        let (port, shutdown, server) = spawn_server(move |server| {
+            server.set_recv_buffer_size(4096);
+            server.set_send_buffer_size(4096);
+            server.add_handle(
+                HTTPMethod::Get,
+                "/",
+                Box::new(move |_| Ok(HTTPResponse::new(200).with_content(&content))),
+            );
+        })?;
+
+        let mut stream = connect(port)?;
+        stream.write_all(request(HTTPMethod::Get, "/").as_bytes())?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert_eq!(response, expected);
+
+        shutdown.send(()).unwrap();
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_listen_with_inline_executor() -> io::Result<()> {
+        // listen doesn't expose the port it is bound to,
+        // so reserve a free one upfront
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+
+        let (tx, rx) = mpsc::channel();
+        let server = std::thread::spawn(move || {
+            let mut server = HTTPServer::new();
+            server.set_handle_executor(HTTPServer::inline_executor());
+            server.set_shutdown(rx);
+            server.add_handle(
+                HTTPMethod::Get,
+                "/",
+                Box::new(|_| Ok(HTTPResponse::new(200).with_content("Hello!"))),
+            );
+            server.listen(port)
+        });
+
+        let mut stream = connect(port)?;
+        stream.write_all(request(HTTPMethod::Get, "/").as_bytes())?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert_eq!(
+            response,
+            HTTPResponse::new(200)
+                .with_header("Connection", "close")
+                .with_content("Hello!")
+                .to_string(),
+        );
+
+        tx.send(()).unwrap();
+        server.join().unwrap()
+    }
+
     #[test]
-    fn test_handle_connection_empty_handles() -> io::Result<()> {
-        let handles = Arc::new(HashMap::new());
-        let mut stream: ReadWriteMock = Default::default();
+    fn test_listen_replace_routes() -> io::Result<()> {
+        let (routes_tx, routes_rx) = mpsc::channel();
+        let (port, tx, server) = spawn_server(move |server| {
+            server.add_handle(
+                HTTPMethod::Get,
+                "/a",
+                Box::new(|_| Ok(HTTPResponse::new(200).with_content("a"))),
+            );
+            routes_tx.send(server.route_table()).unwrap();
+        })?;
+        let routes = routes_rx.recv().unwrap();
 
-        stream.data_to_read = create_pattern(HTTPMethod::Get, "");
+        let get = |path: &str| -> io::Result<String> {
+            let mut stream = connect(port)?;
+            stream.write_all(request(HTTPMethod::Get, path).as_bytes())?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok(response)
+        };
+        assert_eq!(
+            get("/a")?,
+            HTTPResponse::new(200)
+                .with_header("Connection", "close")
+                .with_content("a")
+                .to_string()
+        );
+        assert!(get("/b")?.starts_with("HTTP/1.1 404 Not Found\r\n"));
 
-        handle_connection(Arc::clone(&handles), &mut stream)?;
-        assert_eq!("", stream.data_to_read);
-        assert_eq!("", stream.written_data);
+        // an invalid route leaves the routes as they were
+        let result = routes.replace_routes(vec![
+            (
+                HTTPMethod::Get,
+                "/b",
+                Box::new(|_| Ok(HTTPResponse::new(200).with_content("b"))),
+            ),
+            (
+                HTTPMethod::Get,
+                "c",
+                Box::new(|_| Ok(HTTPResponse::new(200).with_content("c"))),
+            ),
+        ]);
+        assert_eq!(RouteErrorKind::InvalidPath, result.unwrap_err().kind);
+        assert!(get("/a")?.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get("/b")?.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        routes
+            .replace_routes(vec![(
+                HTTPMethod::Get,
+                "/b",
+                Box::new(|_| Ok(HTTPResponse::new(200).with_content("b"))),
+            )])
+            .unwrap();
+        assert!(get("/a")?.starts_with("HTTP/1.1 404 Not Found\r\n"));
         assert_eq!(
-            stream.written_data_flushed,
+            get("/b")?,
+            HTTPResponse::new(200)
+                .with_header("Connection", "close")
+                .with_content("b")
+                .to_string()
+        );
+
+        tx.send(()).unwrap();
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_listen_shutdown_timeout() -> io::Result<()> {
+        let started = Arc::new(AtomicUsize::new(0));
+        let handle_started = Arc::clone(&started);
+        let (tx, rx) = mpsc::channel();
+        let (port_tx, port_rx) = mpsc::channel();
+        // served by the default pool, which is detached once the timeout elapses
+        let server = std::thread::spawn(move || {
+            let mut server = HTTPServer::new();
+            server.set_shutdown(rx);
+            server.with_pool_size(2);
+            server.set_shutdown_timeout(Duration::from_millis(500));
+            server.add_handle(
+                HTTPMethod::Get,
+                "/sleep/:ms",
+                Box::new(move |request| {
+                    handle_started.fetch_add(1, Ordering::SeqCst);
+                    let ms = request.param("ms").unwrap_or_default().parse().unwrap_or(0);
+                    std::thread::sleep(Duration::from_millis(ms));
+                    Ok(HTTPResponse::new(200).with_content("Good morning!"))
+                }),
+            );
+            let (port, server) = server.bind(0)?;
+            port_tx.send(port).unwrap();
+            server.run()
+        });
+        let port = port_rx.recv().unwrap();
+
+        let request = |path: &'static str| {
+            std::thread::spawn(move || -> io::Result<String> {
+                let mut stream = connect(port)?;
+                stream.write_all(request(HTTPMethod::Get, path).as_bytes())?;
+                let mut response = String::new();
+                stream.read_to_string(&mut response)?;
+                Ok(response)
+            })
+        };
+        let fast = request("/sleep/200");
+        let _slow = request("/sleep/5000");
+        wait_for(|| (started.load(Ordering::SeqCst) == 2).then_some(()));
+
+        // the fast connection completes within the timeout, the slow one is cut off
+        let start = Instant::now();
+        tx.send(()).unwrap();
+        server.join().unwrap()?;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(500), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+        assert_eq!(
+            fast.join().unwrap()?,
+            HTTPResponse::new(200)
+                .with_header("Connection", "close")
+                .with_content("Good morning!")
+                .to_string()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_listen_remote_addr() -> io::Result<()> {
+        let (port, tx, server) = spawn_server(|server| {
+            server.add_handle(
+                HTTPMethod::Get,
+                "/ip",
+                Box::new(|request| {
+                    let ip = request.remote_addr().map(|addr| addr.ip().to_string());
+                    Ok(HTTPResponse::new(200).with_content(&ip.unwrap_or_default()))
+                }),
+            );
+        })?;
+
+        let mut stream = connect(port)?;
+        stream.write_all(request(HTTPMethod::Get, "/ip").as_bytes())?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert_eq!(
+            response,
+            HTTPResponse::new(200)
+                .with_header("Connection", "close")
+                .with_content("127.0.0.1")
+                .to_string()
+        );
+
+        tx.send(()).unwrap();
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_listen_keep_alive() -> io::Result<()> {
+        let (port, tx, server) = spawn_server(|server| {
+            server.set_keep_alive_timeout(Duration::from_millis(100));
+            server.add_handle(
+                HTTPMethod::Get,
+                "/:name",
+                Box::new(|request| {
+                    let name = request.param("name").unwrap_or_default();
+                    Ok(HTTPResponse::new(200).with_content(&format!("Hello, {}!", name)))
+                }),
+            );
+        })?;
+
+        // pipelined requests, of which the last one asks for the connection to be closed
+        let mut stream = connect(port)?;
+        stream.write_all(
+            b"GET /foo HTTP/1.1\r\nConnection: keep-alive\r\n\r\n\
+              GET /bar HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert_eq!(
+            response,
             format!(
-                "HTTP/1.1 404\r\nContent-Length: {}\r\n\r\n{}",
-                HTTP_CONTENT_404.len(),
-                HTTP_CONTENT_404,
+                "{}{}",
+                HTTPResponse::new(200)
+                    .with_content("Hello, foo!")
+                    .with_header("Connection", "keep-alive"),
+                HTTPResponse::new(200)
+                    .with_content("Hello, bar!")
+                    .with_header("Connection", "close"),
+            )
+        );
+
+        // closed once idle for longer than the keep-alive timeout
+        let mut stream = connect(port)?;
+        stream.write_all(b"GET /foo HTTP/1.1\r\nConnection: keep-alive\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert_eq!(
+            response,
+            HTTPResponse::new(200)
+                .with_content("Hello, foo!")
+                .with_header("Connection", "keep-alive")
+                .to_string()
+        );
+
+        // HTTP/1.1 connections are kept alive unless asked otherwise
+        let mut stream = connect(port)?;
+        stream.write_all(
+            b"GET /foo HTTP/1.1\r\n\r\n\
+              GET /bar HTTP/1.1\r\nConnection: close\r\n\r\n",
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert_eq!(
+            response,
+            format!(
+                "{}{}",
+                HTTPResponse::new(200).with_content("Hello, foo!"),
+                HTTPResponse::new(200)
+                    .with_content("Hello, bar!")
+                    .with_header("Connection", "close"),
+            )
+        );
+
+        // HTTP/1.0 connections are only kept alive when asked for,
+        // such that the request after one which doesn't ask for it is never answered
+        let mut stream = connect(port)?;
+        stream.write_all(
+            b"GET /foo HTTP/1.0\r\nConnection: keep-alive\r\n\r\n\
+              GET /bar HTTP/1.0\r\n\r\n\
+              GET /baz HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert_eq!(
+            response,
+            format!(
+                "{}{}",
+                HTTPResponse::new(200)
+                    .with_content("Hello, foo!")
+                    .with_header("Connection", "keep-alive"),
+                HTTPResponse::new(200)
+                    .with_content("Hello, bar!")
+                    .with_header("Connection", "close"),
             )
         );
 
+        tx.send(()).unwrap();
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_listen_keep_alive_shutdown() -> io::Result<()> {
+        let (port, tx, server) = spawn_server(|server| {
+            let pool = ThreadPool::new(1).unwrap();
+            server.set_handle_executor(Box::new(move |f| pool.execute(f)));
+            server.set_keep_alive_timeout(Duration::from_secs(30));
+            server.add_handle(
+                HTTPMethod::Get,
+                "/",
+                Box::new(|_| Ok(HTTPResponse::new(200).with_content("Hi!"))),
+            );
+        })?;
+
+        let mut stream = connect(port)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n")?;
+        let expected = HTTPResponse::new(200).with_content("Hi!").to_string();
+        let mut response = vec![0; expected.len()];
+        stream.read_exact(&mut response)?;
+        assert_eq!(String::from_utf8_lossy(&response), expected);
+
+        // the idle connection is closed, rather than waited on until the keep-alive timeout
+        let start = Instant::now();
+        tx.send(()).unwrap();
+        assert_eq!(stream.read(&mut [0; 1])?, 0);
+        server.join().unwrap()?;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_listen_keep_alive_yields_to_pending() -> io::Result<()> {
+        let (port, tx, server) = spawn_server(|server| {
+            let pool = ThreadPool::new(1).unwrap();
+            server.set_handle_executor(Box::new(move |f| pool.execute(f)));
+            server.set_keep_alive_timeout(Duration::from_secs(30));
+            server.add_handle(
+                HTTPMethod::Get,
+                "/",
+                Box::new(|_| Ok(HTTPResponse::new(200))),
+            );
+        })?;
+        let expected = HTTPResponse::new(200)
+            .with_header("Content-Length", "0")
+            .to_string();
+
+        // kept alive, holding on to the only thread of the pool while idle
+        let mut idle = connect(port)?;
+        idle.set_read_timeout(Some(Duration::from_secs(5)))?;
+        idle.write_all(b"GET / HTTP/1.1\r\n\r\n")?;
+        let mut response = vec![0; expected.len()];
+        idle.read_exact(&mut response)?;
+        assert_eq!(String::from_utf8_lossy(&response), expected);
+
+        // closed as soon as another connection waits on the pool
+        let start = Instant::now();
+        let mut stream = connect(port)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n")?;
+        let mut response = vec![0; expected.len()];
+        stream.read_exact(&mut response)?;
+        assert_eq!(String::from_utf8_lossy(&response), expected);
+        assert_eq!(idle.read(&mut [0; 1])?, 0);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        drop(stream);
+        tx.send(()).unwrap();
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_handle_connection_coalesce_requests() -> io::Result<()> {
+        const REQUESTS: usize = 5;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handle_calls = Arc::clone(&calls);
+        let (release_tx, release_rx) = mpsc::channel();
+        let release_rx = Mutex::new(release_rx);
+        let mut server = HTTPServer::new();
+        server.set_coalesce_requests(true);
+        server.add_handle(
+            HTTPMethod::Get,
+            "/slow",
+            Box::new(move |_| {
+                let call = handle_calls.fetch_add(1, Ordering::SeqCst) + 1;
+                release_rx.lock().unwrap().recv().unwrap();
+                Ok(HTTPResponse::new(200).with_content(&format!("call {}", call)))
+            }),
+        );
+        let ctx = &server.ctx;
+        // the flight is shared by the map and each of the requests waiting on it
+        let waiting = || {
+            let in_flight = ctx.in_flight.as_ref().unwrap().lock().unwrap();
+            in_flight
+                .values()
+                .map(|flight| Arc::strong_count(flight) - 1)
+                .max()
+                .unwrap_or(0)
+        };
+
+        let barrier = Barrier::new(REQUESTS);
+        let responses = std::thread::scope(|scope| {
+            let clients: Vec<_> = (0..REQUESTS)
+                .map(|_| {
+                    scope.spawn(|| -> io::Result<String> {
+                        let mut stream = ReadWriteMock {
+                            data_to_read: format!(
+                                "{}\r\n",
+                                create_pattern(HTTPMethod::Get, "/slow")
+                            ),
+                            ..Default::default()
+                        };
+                        barrier.wait();
+                        handle_connection(ctx, &mut stream, None)?;
+                        Ok(stream.written_data_flushed)
+                    })
+                })
+                .collect();
+            while calls.load(Ordering::SeqCst) == 0 || waiting() < REQUESTS - 1 {
+                std::thread::yield_now();
+            }
+            release_tx.send(()).unwrap();
+            clients
+                .into_iter()
+                .map(|client| client.join().unwrap())
+                .collect::<io::Result<Vec<_>>>()
+        })?;
+        for response in responses {
+            assert_eq!(
+                response,
+                HTTPResponse::new(200).with_content("call 1").to_string()
+            );
+        }
+        assert_eq!(1, calls.load(Ordering::SeqCst));
         Ok(())
     }
 
     #[test]
-    fn test_handle_connection_with_handles() -> io::Result<()> {
-        let mut map: HashMap<String, HTTPHandle> = HashMap::new();
-        map.insert(
-            create_pattern(HTTPMethod::Post, ""),
-            Box::new(|| Ok(HTTPResponse::new(200))),
+    fn test_listen_closes_connection() -> io::Result<()> {
+        let (port, tx, server) = spawn_server(|server| {
+            server.add_handle(
+                HTTPMethod::Get,
+                "/",
+                Box::new(|_| Ok(HTTPResponse::new(200).with_content("Bye!"))),
+            );
+        })?;
+
+        let mut stream = connect(port)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.write_all(request(HTTPMethod::Get, "/").as_bytes())?;
+        let mut response = Vec::new();
+        // only returns once the server closed its side of the connection
+        stream.read_to_end(&mut response)?;
+        assert_eq!(
+            String::from_utf8_lossy(&response),
+            HTTPResponse::new(200)
+                .with_header("Connection", "close")
+                .with_content("Bye!")
+                .to_string(),
+        );
+        assert_eq!(0, stream.read(&mut [0; 1])?);
+
+        tx.send(()).unwrap();
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_listen_max_body_size() -> io::Result<()> {
+        let (port, tx, server) = spawn_server(|server| {
+            server.set_max_body_size(4);
+            server.add_handle(
+                HTTPMethod::Post,
+                "/",
+                Box::new(|_| Ok(HTTPResponse::new(200))),
+            );
+        })?;
+
+        // the body isn't read, yet doesn't reset the connection before the response is read
+        let mut stream = connect(port)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.write_all(
+            format!(
+                "POST / HTTP/1.1\r\nContent-Length: 16384\r\n\r\n{}",
+                "a".repeat(16384)
+            )
+            .as_bytes(),
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert_eq!(
+            response,
+            HTTPResponse::new(413)
+                .with_header("Connection", "close")
+                .to_string()
         );
-        map.insert(
-            create_pattern(HTTPMethod::Get, "/foo"),
-            Box::new(|| Ok(HTTPResponse::new(200).with_content("Foo, bar!"))),
+
+        tx.send(()).unwrap();
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_listen_request_body_timeout() -> io::Result<()> {
+        let (port, tx, server) = spawn_server(|server| {
+            server.set_read_timeout(Duration::from_millis(100));
+            server.add_handle(
+                HTTPMethod::Post,
+                "/",
+                Box::new(|_| Ok(HTTPResponse::new(200))),
+            );
+        })?;
+
+        let mut stream = connect(port)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        // the body is incomplete, but the connection is kept open
+        stream.write_all(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert_eq!(
+            response,
+            HTTPResponse::new(408)
+                .with_header("Connection", "close")
+                .to_string()
         );
-        let handles = Arc::new(map);
-        let mut stream: ReadWriteMock = Default::default();
 
-        stream.data_to_read = create_pattern(HTTPMethod::Get, "");
+        tx.send(()).unwrap();
+        server.join().unwrap()
+    }
 
-        handle_connection(Arc::clone(&handles), &mut stream)?;
-        assert_eq!("", stream.data_to_read);
-        assert_eq!("", stream.written_data);
+    #[test]
+    fn test_listen_accept_rate() -> io::Result<()> {
+        let (port, tx, server) = spawn_server(|server| {
+            server.set_accept_rate(20);
+            server.add_handle(
+                HTTPMethod::Get,
+                "/",
+                Box::new(|_| Ok(HTTPResponse::new(200))),
+            );
+        })?;
+
+        // connect all at once, queueing them on the listener
+        let mut streams = (0..10)
+            .map(|_| connect(port))
+            .collect::<io::Result<Vec<_>>>()?;
+        let start = Instant::now();
+        for stream in &mut streams {
+            stream.write_all(request(HTTPMethod::Get, "/").as_bytes())?;
+        }
+        for stream in &mut streams {
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            assert_eq!(
+                response,
+                HTTPResponse::new(200)
+                    .with_header("Connection", "close")
+                    .to_string()
+            );
+        }
+        // at 20 per second, the last connection is accepted 9 intervals of 50ms after the first,
+        // with some slack as the first one may be accepted before the timing started
+        assert!(start.elapsed() >= Duration::from_millis(350));
+
+        tx.send(()).unwrap();
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_listen_clock() -> io::Result<()> {
+        let clock = FakeClock::new();
+        let server_clock = clock.clone();
+        let (port, tx, server) = spawn_server(move |server| {
+            let pool = ThreadPool::new(2).unwrap();
+            server.set_handle_executor(HTTPServer::pool_executor(Arc::new(pool)));
+            server.set_clock(server_clock);
+            server.set_accept_rate(1);
+            server.set_keep_alive_timeout(Duration::from_secs(60));
+            server.add_handle(
+                HTTPMethod::Get,
+                "/",
+                Box::new(|_| Ok(HTTPResponse::new(200))),
+            );
+        })?;
+        let response = HTTPResponse::new(200)
+            .with_header("Content-Length", "0")
+            .to_string();
+        let is_timeout = |e: &io::Error| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            )
+        };
+
+        let mut first = connect(port)?;
+        first.write_all(create_pattern(HTTPMethod::Get, "/").as_bytes())?;
+        first.write_all(b"\r\n")?;
+        let mut received = vec![0; response.len()];
+        first.read_exact(&mut received)?;
+        assert_eq!(response.as_bytes(), &received[..]);
+
+        // neither is the first connection closed, nor the second one accepted, until time passes
+        let mut second = connect(port)?;
+        second.write_all(request(HTTPMethod::Get, "/").as_bytes())?;
+        for stream in [&mut first, &mut second] {
+            stream.set_read_timeout(Some(Duration::from_millis(200)))?;
+            let err = stream.read(&mut [0; 1]).unwrap_err();
+            assert!(is_timeout(&err), "{}", err);
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        }
+
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(0, first.read(&mut [0; 1])?);
+        let mut received = String::new();
+        second.read_to_string(&mut received)?;
         assert_eq!(
-            stream.written_data_flushed,
-            HTTPResponse::new(404)
-                .with_content(HTTP_CONTENT_404)
+            received,
+            HTTPResponse::new(200)
+                .with_header("Connection", "close")
+                .to_string()
+        );
+
+        tx.send(()).unwrap();
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_listen_with_shared_pool() -> io::Result<()> {
+        let pool = Arc::new(ThreadPool::new(2).unwrap());
+        let mut servers = Vec::new();
+        for content in ["main", "admin"] {
+            let pool = Arc::clone(&pool);
+            servers.push(spawn_server(move |server| {
+                server.set_handle_executor(HTTPServer::pool_executor(pool));
+                server.add_handle(
+                    HTTPMethod::Get,
+                    "/",
+                    Box::new(move |_| Ok(HTTPResponse::new(200).with_content(content))),
+                );
+            })?);
+        }
+
+        for ((port, _, _), content) in servers.iter().zip(["main", "admin"]) {
+            let mut stream = connect(*port)?;
+            stream.write_all(request(HTTPMethod::Get, "/").as_bytes())?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            assert_eq!(
+                response,
+                HTTPResponse::new(200)
+                    .with_header("Connection", "close")
+                    .with_content(content)
+                    .to_string()
+            );
+        }
+        wait_for(|| Some(()).filter(|_| pool.stats().completed_total == 2));
+
+        for (_, tx, server) in servers {
+            tx.send(()).unwrap();
+            server.join().unwrap()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_listen_paused() -> io::Result<()> {
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+
+        let (tx, rx) = mpsc::channel();
+        let (pause_tx, pause_rx) = mpsc::channel();
+        let server = std::thread::spawn(move || {
+            let mut server = HTTPServer::new();
+            server.set_handle_executor(HTTPServer::inline_executor());
+            server.set_shutdown(rx);
+            server.add_handle(
+                HTTPMethod::Get,
+                "/",
+                Box::new(|_| Ok(HTTPResponse::new(200).with_content("Hello!"))),
+            );
+            let pause = server.pause_handle();
+            pause.pause();
+            pause_tx.send(pause).unwrap();
+            server.listen(port)
+        });
+        let pause = pause_rx.recv().unwrap();
+
+        // the connection is established, but not served while paused
+        let mut stream = connect(port)?;
+        stream.write_all(request(HTTPMethod::Get, "/").as_bytes())?;
+        stream.set_read_timeout(Some(Duration::from_millis(200)))?;
+        let mut buffer = [0; 32];
+        let err = stream.read(&mut buffer).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ));
+
+        pause.resume();
+        stream.set_read_timeout(None)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert_eq!(
+            response,
+            HTTPResponse::new(200)
+                .with_header("Connection", "close")
+                .with_content("Hello!")
                 .to_string(),
         );
 
-        stream.clear();
-        stream.data_to_read = create_pattern(HTTPMethod::Get, "/foo");
+        tx.send(()).unwrap();
+        server.join().unwrap()
+    }
 
-        handle_connection(Arc::clone(&handles), &mut stream)?;
-        assert_eq!("", stream.data_to_read);
-        assert_eq!("", stream.written_data);
+    #[test]
+    fn test_rejection_response() {
         assert_eq!(
-            stream.written_data_flushed,
-            HTTPResponse::new(200).with_content("Foo, bar!").to_string(),
+            "HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\n\r\n",
+            rejection_response(None).to_string()
+        );
+        assert_eq!(
+            "HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nRetry-After: 2\r\n\r\n",
+            rejection_response(Some(Duration::from_secs(2))).to_string()
+        );
+        assert_eq!(
+            "HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nRetry-After: 2\r\n\r\n",
+            rejection_response(Some(Duration::from_millis(1500))).to_string()
         );
+    }
 
-        stream.clear();
-        stream.data_to_read = create_pattern(HTTPMethod::Post, "/");
+    #[test]
+    fn test_reject_connection() -> io::Result<()> {
+        // the unread request is drained, up to a bound
+        for (len, left) in [(16, 0), (MAX_DRAIN_BYTES + 16, 16)] {
+            let mut stream = ReadWriteMock {
+                data_to_read: "a".repeat(len),
+                ..Default::default()
+            };
+            reject_connection(&mut stream, None)?;
+            assert!(stream.write_shut_down.get());
+            assert_eq!(
+                stream.written_data_flushed,
+                rejection_response(None).to_string()
+            );
+            assert_eq!(stream.data_to_read.len(), left);
+        }
+        Ok(())
+    }
 
-        handle_connection(Arc::clone(&handles), &mut stream)?;
-        assert_eq!("", stream.data_to_read);
-        assert_eq!("", stream.written_data);
+    #[test]
+    fn test_listen_max_pending() -> io::Result<()> {
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+
+        let (tx, rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        let server = std::thread::spawn(move || {
+            let mut server = HTTPServer::new();
+            let pool = ThreadPool::new(1).unwrap();
+            server.set_handle_executor(Box::new(move |f| pool.execute(f)));
+            server.set_max_pending(1);
+            server.set_retry_after(Duration::from_secs(30));
+            server.set_shutdown(rx);
+            server.add_handle(
+                HTTPMethod::Get,
+                "/",
+                Box::new(move |_| {
+                    // block until released
+                    let _ = release_rx.lock().unwrap().recv();
+                    Ok(HTTPResponse::new(200))
+                }),
+            );
+            server.listen(port)
+        });
+
+        // first connection is being handled, second one is pending
+        let mut streams = Vec::new();
+        for _ in 0..2 {
+            let mut stream = connect(port)?;
+            stream.write_all(request(HTTPMethod::Get, "/").as_bytes())?;
+            streams.push(stream);
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        // any other connection is rejected, without its unread request resetting it
+        let mut stream = connect(port)?;
+        stream.write_all(
+            format!(
+                "POST / HTTP/1.1\r\nContent-Length: 16384\r\n\r\n{}",
+                "a".repeat(16384)
+            )
+            .as_bytes(),
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
         assert_eq!(
-            stream.written_data_flushed,
-            HTTPResponse::new(200).to_string(),
+            response,
+            HTTPResponse::new(503)
+                .with_header("Connection", "close")
+                .with_header("Retry-After", "30")
+                .to_string()
+        );
+
+        drop(release_tx);
+        for mut stream in streams {
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            assert_eq!(
+                response,
+                HTTPResponse::new(200)
+                    .with_header("Connection", "close")
+                    .to_string()
+            );
+        }
+
+        tx.send(()).unwrap();
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn test_listen_active_connections() -> io::Result<()> {
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+
+        let (tx, rx) = mpsc::channel();
+        let (tracker_tx, tracker_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        let server = std::thread::spawn(move || {
+            let mut server = HTTPServer::new();
+            server.set_shutdown(rx);
+            server.add_handle(
+                HTTPMethod::Get,
+                "/slow",
+                Box::new(move |_| {
+                    let _ = release_rx.lock().unwrap().recv();
+                    Ok(HTTPResponse::new(200))
+                }),
+            );
+            tracker_tx.send(server.connection_tracker()).unwrap();
+            server.listen(port)
+        });
+        let tracker = tracker_rx.recv().unwrap();
+        assert!(tracker.active_connections().is_empty());
+
+        let mut stream = connect(port)?;
+        stream.write_all(request(HTTPMethod::Get, "/slow").as_bytes())?;
+        let connections = wait_for(|| {
+            let connections = tracker.active_connections();
+            Some(connections).filter(|c| !c.is_empty())
+        });
+        assert_eq!(1, connections.len());
+        assert_eq!(stream.local_addr()?, connections[0].remote_addr);
+        assert!(connections[0].age() < Duration::from_secs(5));
+
+        drop(release_tx);
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert_eq!(
+            response,
+            HTTPResponse::new(200)
+                .with_header("Connection", "close")
+                .to_string()
+        );
+        wait_for(|| Some(()).filter(|_| tracker.active_connections().is_empty()));
+
+        tx.send(()).unwrap();
+        server.join().unwrap()
+    }
+
+    // Listen in the background on a free port using the inline executor,
+    // returning that port together with the means to stop and join the server.
+    // Clock which only moves when advanced.
+    struct FakeClock(Mutex<Instant>);
+
+    impl FakeClock {
+        fn new() -> Arc<FakeClock> {
+            Arc::new(FakeClock(Mutex::new(Instant::now())))
+        }
+
+        fn advance(&self, duration: Duration) {
+            *self.0.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn spawn_server(
+        configure: impl FnOnce(&mut HTTPServer) + Send + 'static,
+    ) -> io::Result<(
+        u16,
+        mpsc::Sender<()>,
+        std::thread::JoinHandle<io::Result<()>>,
+    )> {
+        let (tx, rx) = mpsc::channel();
+        let (port_tx, port_rx) = mpsc::channel();
+        let server = std::thread::spawn(move || {
+            let mut server = HTTPServer::new();
+            server.set_handle_executor(HTTPServer::inline_executor());
+            server.set_shutdown(rx);
+            configure(&mut server);
+            let (port, server) = server.bind(0)?;
+            port_tx.send(port).unwrap();
+            server.run()
+        });
+        match port_rx.recv() {
+            Ok(port) => Ok((port, tx, server)),
+            Err(_) => Err(server.join().unwrap().unwrap_err()),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_bind_unix_listener() -> io::Result<()> {
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        let path = std::env::temp_dir().join(format!("webservice-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+
+        let (tx, rx) = mpsc::channel();
+        let mut server = HTTPServer::new();
+        server.set_handle_executor(HTTPServer::inline_executor());
+        server.set_shutdown(rx);
+        server.add_handle(
+            HTTPMethod::Get,
+            "/",
+            Box::new(|_| Ok(HTTPResponse::new(200).with_content("Hello, Unix!"))),
+        );
+        let server = server.bind_listener(listener)?;
+
+        let client_path = path.clone();
+        let client = std::thread::spawn(move || {
+            let response = UnixStream::connect(&client_path).and_then(|mut stream| {
+                stream.write_all(request(HTTPMethod::Get, "/").as_bytes())?;
+                let mut response = String::new();
+                stream.read_to_string(&mut response)?;
+                Ok(response)
+            });
+            tx.send(()).unwrap();
+            response
+        });
+        server.run()?;
+        fs::remove_file(&path)?;
+
+        assert_eq!(
+            client.join().unwrap()?,
+            HTTPResponse::new(200)
+                .with_header("Connection", "close")
+                .with_content("Hello, Unix!")
+                .to_string()
         );
+        Ok(())
+    }
+
+    #[test]
+    fn test_accept_poll_interval() -> io::Result<()> {
+        let (_, tx, server) = spawn_server(|server| {
+            server.set_accept_poll_interval(Duration::from_millis(20));
+        })?;
+        std::thread::sleep(Duration::from_millis(100));
+
+        // shutting down idle is noticed within about the poll interval
+        let start = Instant::now();
+        tx.send(()).unwrap();
+        server.join().unwrap()?;
+        let elapsed = start.elapsed();
+        assert!(elapsed < Duration::from_millis(200), "{:?}", elapsed);
+        Ok(())
+    }
 
+    #[test]
+    fn test_run_invalid_pool_size() -> io::Result<()> {
+        let mut server = HTTPServer::new();
+        server.with_pool_size(0);
+        let (_, server) = server.bind(0)?;
+        let err = server.run().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        let mut server = HTTPServer::new();
+        server.with_pool_size(0);
+        let err = server.listen(0).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        assert!(err.to_string().contains("InvalidSize"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bind_port_zero() -> io::Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let mut server = HTTPServer::new();
+        server.set_handle_executor(HTTPServer::inline_executor());
+        server.set_shutdown(rx);
+        let (port, server) = server.bind(0)?;
+        assert_ne!(0, port);
+        assert_eq!(port, server.local_addr()?.port());
+
+        let client = std::thread::spawn(move || {
+            let response = connect(port).and_then(|mut stream| {
+                stream.write_all(b"GET / HTTP/1.1\r\n\r\n")?;
+                let mut response = String::new();
+                stream.read_to_string(&mut response)?;
+                Ok(response)
+            });
+            tx.send(()).unwrap();
+            response
+        });
+        server.run()?;
+
+        assert!(client
+            .join()
+            .unwrap()?
+            .starts_with("HTTP/1.1 404 Not Found\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_listen_addr_in_use() -> io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let err = HTTPServer::new()
+            .listen_addr(listener.local_addr()?)
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::AddrInUse, err.kind());
         Ok(())
     }
+
+    #[test]
+    fn test_listen_addr_ipv6() -> io::Result<()> {
+        let port = match TcpListener::bind("[::1]:0") {
+            Ok(listener) => listener.local_addr()?.port(),
+            // IPv6 is not available on this machine
+            Err(_) => return Ok(()),
+        };
+        let (tx, rx) = mpsc::channel();
+        let server = std::thread::spawn(move || {
+            let mut server = HTTPServer::new();
+            server.set_handle_executor(HTTPServer::inline_executor());
+            server.set_shutdown(rx);
+            server.add_handle(
+                HTTPMethod::Get,
+                "/",
+                Box::new(|_| Ok(HTTPResponse::new(200).with_content("hello"))),
+            );
+            server.listen_addr(("::1", port))
+        });
+
+        let mut stream = wait_for(|| TcpStream::connect(("::1", port)).ok());
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n")?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("hello"));
+
+        tx.send(()).unwrap();
+        server.join().unwrap()
+    }
+
+    // Poll until the given function returns a value, panicking if it takes too long.
+    fn wait_for<T>(f: impl Fn() -> Option<T>) -> T {
+        for _ in 0..100 {
+            if let Some(value) = f() {
+                return value;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("timed out waiting for condition");
+    }
+
+    // Complete request of a client which doesn't keep the connection alive.
+    fn request(method: HTTPMethod, path: &str) -> String {
+        format!("{}Connection: close\r\n\r\n", create_pattern(method, path))
+    }
+
+    fn connect(port: u16) -> io::Result<TcpStream> {
+        // give the server some time to start listening
+        let mut attempts = 0;
+        loop {
+            match TcpStream::connect(("127.0.0.1", port)) {
+                Ok(stream) => return Ok(stream),
+                Err(e) if attempts >= 50 => return Err(e),
+                Err(_) => {
+                    attempts += 1;
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+        }
+    }
 }
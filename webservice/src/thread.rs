@@ -33,10 +33,12 @@
 
 use std::fmt;
 use std::result;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
+use std::time::{Duration, Instant};
 
 /// `PoolError` is the error used for any errors resulting
 /// from creating or using a [ThreadPool](self::ThreadPool).
@@ -58,6 +60,10 @@ pub enum PoolErrorKind {
     /// documentation of [ThreadPool::new](self::ThreadPool::new)
     /// to find what size is appropriate.
     InvalidSize,
+    /// Indicates that weighted work was rejected as the summed weight of
+    /// the queued work would exceed the budget of the [ThreadPool](self::ThreadPool),
+    /// refer to the documentation of [ThreadPool::set_max_queued_weight](self::ThreadPool::set_max_queued_weight).
+    BudgetExceeded,
 }
 
 /// Result alias type used for all functions within this create which
@@ -79,7 +85,94 @@ impl fmt::Display for PoolError {
 /// it will handle in order to not expose itself to a DDoS attack.
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
+    dispatcher: Dispatcher,
+    receivers: Vec<Arc<Mutex<mpsc::Receiver<Message>>>>,
+    detached: AtomicBool,
+}
+
+// Sends messages to the workers of a pool, over either a single channel shared
+// by all workers, or a channel per worker in case of round-robin dispatching.
+#[derive(Clone)]
+struct Dispatcher {
+    senders: Vec<mpsc::Sender<Message>>,
+    next: Arc<AtomicUsize>,
+    counters: Arc<Mutex<Counters>>,
+}
+
+// Reason a job could not be dispatched.
+#[derive(Debug)]
+enum DispatchError {
+    BudgetExceeded,
+    Disconnected,
+}
+
+impl Dispatcher {
+    fn dispatch(&self, job: Job, weight: usize) -> result::Result<(), DispatchError> {
+        // counted upfront, as a worker could otherwise finish the job before it is counted
+        if !self.counters.lock().unwrap().queue(1, weight) {
+            return Err(DispatchError::BudgetExceeded);
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        let result = self.senders[next % self.senders.len()].send(Message::NewJob(job, weight));
+        if result.is_err() {
+            self.counters.lock().unwrap().dequeue(1, weight);
+            return Err(DispatchError::Disconnected);
+        }
+        Ok(())
+    }
+
+    fn send_to(&self, worker_id: usize, message: Message) {
+        self.senders[worker_id % self.senders.len()]
+            .send(message)
+            .unwrap();
+    }
+}
+
+/// Snapshot of the statistics of a [ThreadPool](self::ThreadPool),
+/// with all numbers taken at the same moment, such that they are consistent with each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Amount of threads of the pool.
+    pub size: usize,
+    /// Amount of jobs being executed.
+    pub active: usize,
+    /// Amount of jobs waiting to be executed.
+    pub queued: usize,
+    /// Amount of jobs executed so far.
+    pub completed_total: usize,
+    /// Highest amount of jobs that were waiting to be executed at once so far.
+    pub peak_queue_depth: usize,
+}
+
+// Counters shared between a pool and its workers, updated together under a single lock.
+#[derive(Default)]
+struct Counters {
+    active: usize,
+    queued: usize,
+    completed_total: usize,
+    peak_queue_depth: usize,
+    queued_weight: usize,
+    max_queued_weight: Option<usize>,
+}
+
+impl Counters {
+    // Queue jobs of the given summed weight, unless it would exceed the weight budget.
+    fn queue(&mut self, n: usize, weight: usize) -> bool {
+        if let Some(max) = self.max_queued_weight {
+            if self.queued_weight.saturating_add(weight) > max {
+                return false;
+            }
+        }
+        self.queued += n;
+        self.queued_weight += weight;
+        self.peak_queue_depth = self.peak_queue_depth.max(self.queued);
+        true
+    }
+
+    fn dequeue(&mut self, n: usize, weight: usize) {
+        self.queued -= n;
+        self.queued_weight -= weight;
+    }
 }
 
 impl ThreadPool {
@@ -92,6 +185,37 @@ impl ThreadPool {
     /// A [PoolError](self::PoolError) is returned with kind [PoolErrorKind::InvalidSize](self::PoolErrorKind::InvalidSize)
     /// if a size of 0 is given, all strictly positive integers can be used as a valid size up to the max usize value.
     pub fn new(size: usize) -> Result<ThreadPool> {
+        ThreadPool::create(size, false)
+    }
+
+    /// Create a new ThreadPool which gives each of its threads its own queue,
+    /// dispatching the scheduled work over them in a round-robin fashion.
+    ///
+    /// This spreads the work more evenly over the threads than the single queue
+    /// used by a pool created with [new](self::ThreadPool::new), where all threads
+    /// contend for the same lock, at the cost of work possibly waiting on a busy thread
+    /// while another one is idle.
+    ///
+    /// # Errors
+    ///
+    /// Same as [ThreadPool::new](self::ThreadPool::new).
+    pub fn new_round_robin(size: usize) -> Result<ThreadPool> {
+        ThreadPool::create(size, true)
+    }
+
+    /// Create a new ThreadPool with as many threads as the parallelism
+    /// available to the program, as reported by [std::thread::available_parallelism],
+    /// falling back to a single thread if it cannot be determined.
+    ///
+    /// # Errors
+    ///
+    /// Same as [ThreadPool::new](self::ThreadPool::new).
+    pub fn with_available_parallelism() -> Result<ThreadPool> {
+        let size = thread::available_parallelism().map_or(1, |n| n.get());
+        ThreadPool::new(size)
+    }
+
+    fn create(size: usize, round_robin: bool) -> Result<ThreadPool> {
         if size == 0 {
             return Err(PoolError {
                 kind: PoolErrorKind::InvalidSize,
@@ -99,16 +223,32 @@ impl ThreadPool {
             });
         }
 
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..if round_robin { size } else { 1 })
+            .map(|_| {
+                let (sender, receiver) = mpsc::channel();
+                (sender, Arc::new(Mutex::new(receiver)))
+            })
+            .unzip();
+
+        let counters = Arc::new(Mutex::new(Counters::default()));
 
         let mut workers = Vec::with_capacity(size);
 
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            let receiver = &receivers[id % receivers.len()];
+            workers.push(Worker::new(id, Arc::clone(receiver), Arc::clone(&counters)));
         }
 
-        Ok(ThreadPool { workers, sender })
+        Ok(ThreadPool {
+            workers,
+            dispatcher: Dispatcher {
+                senders,
+                next: Arc::new(AtomicUsize::new(0)),
+                counters,
+            },
+            receivers,
+            detached: AtomicBool::new(false),
+        })
     }
 
     /// Schedule work to be done by one of the pre-allocated threads
@@ -122,30 +262,195 @@ impl ThreadPool {
     {
         let job = Box::new(f);
 
-        self.sender.send(Message::NewJob(job)).unwrap();
+        self.dispatcher.dispatch(job, 0).unwrap();
     }
-}
 
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
+    /// Schedule work to be done, same as [execute](self::ThreadPool::execute),
+    /// accounting for the given weight of the work while it is waiting to be executed.
+    ///
+    /// The weight is up to the caller, e.g. the amount of bytes captured by the work,
+    /// such that the memory held by queued work can be bounded
+    /// using [set_max_queued_weight](self::ThreadPool::set_max_queued_weight).
+    ///
+    /// # Errors
+    ///
+    /// A [PoolError](self::PoolError) is returned with kind [PoolErrorKind::BudgetExceeded](self::PoolErrorKind::BudgetExceeded)
+    /// if the summed weight of the queued work would exceed the budget, in which case the work is not scheduled.
+    pub fn execute_weighted<F>(&self, weight: usize, f: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let job = Box::new(f);
+
+        match self.dispatcher.dispatch(job, weight) {
+            Err(DispatchError::BudgetExceeded) => Err(PoolError {
+                kind: PoolErrorKind::BudgetExceeded,
+                message: "summed weight of queued work would exceed the budget",
+            }),
+            result => {
+                result.unwrap();
+                Ok(())
+            }
+        }
+    }
+
+    /// Limit the summed weight of work scheduled using
+    /// [execute_weighted](self::ThreadPool::execute_weighted) that is waiting to be executed,
+    /// rejecting work which would exceed it. Work stops counting towards it once it is being executed.
+    ///
+    /// Work scheduled using [execute](self::ThreadPool::execute) has no weight,
+    /// and is as such never rejected.
+    pub fn set_max_queued_weight(&mut self, budget: usize) {
+        self.dispatcher.counters.lock().unwrap().max_queued_weight = Some(budget);
+    }
+
+    /// Schedule work to be done repeatedly, every period, by the threads
+    /// of this [ThreadPool](self::ThreadPool), until the returned
+    /// [IntervalHandle](self::IntervalHandle) is cancelled or dropped.
+    ///
+    /// A run is skipped when the previous one hasn't finished yet,
+    /// such that runs taking longer than the period don't pile up.
+    /// Runs stop once the pool is dropped.
+    pub fn execute_interval<F>(&self, period: Duration, f: F) -> IntervalHandle
+    where
+        F: Fn() + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let dispatcher = self.dispatcher.clone();
+        // runs never overlap, so the lock is never contended,
+        // yet required to share the closure without it having to be Sync
+        let f = Arc::new(Mutex::new(f));
+        let running = Arc::new(AtomicBool::new(false));
+
+        thread::spawn(move || {
+            let mut next = Instant::now() + period;
+            // until cancelled or dropped
+            while let Err(mpsc::RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(next.saturating_duration_since(Instant::now()))
+            {
+                next += period;
+
+                if running.swap(true, Ordering::SeqCst) {
+                    log::debug!("Skipped interval run, as the previous one is still running.");
+                    continue;
+                }
+                let f = Arc::clone(&f);
+                let running = Arc::clone(&running);
+                let job = Box::new(move || {
+                    (f.lock().unwrap())();
+                    running.store(false, Ordering::SeqCst);
+                });
+                if dispatcher.dispatch(job, 0).is_err() {
+                    // the pool is gone
+                    break;
+                }
+            }
+        });
+
+        IntervalHandle { _stop: stop }
+    }
+
+    /// Return for each thread of this [ThreadPool](self::ThreadPool),
+    /// ordered by thread, the amount of jobs it started executing so far.
+    pub fn worker_stats(&self) -> Vec<usize> {
+        self.workers
+            .iter()
+            .map(|worker| worker.jobs.load(Ordering::SeqCst))
+            .collect()
+    }
+
+    /// Return a consistent snapshot of the statistics of this [ThreadPool](self::ThreadPool).
+    pub fn stats(&self) -> PoolStats {
+        let counters = self.dispatcher.counters.lock().unwrap();
+        PoolStats {
+            size: self.workers.len(),
+            active: counters.active,
+            queued: counters.queued,
+            completed_total: counters.completed_total,
+            peak_queue_depth: counters.peak_queue_depth,
+        }
+    }
+
+    /// Shut down this [ThreadPool](self::ThreadPool) without running
+    /// the work which is still waiting to be executed, returning the amount
+    /// of scheduled jobs which were discarded as such.
+    ///
+    /// Work already being executed is finished first, same as when
+    /// the pool is dropped, which would run all scheduled work instead.
+    pub fn shutdown_now(mut self) -> usize {
+        let (dropped, weight) = self
+            .receivers
+            .iter()
+            .flat_map(|receiver| {
+                // while locked no worker can start a new job from this channel
+                let receiver = receiver.lock().unwrap();
+                receiver
+                    .try_iter()
+                    .filter_map(|message| match message {
+                        Message::NewJob(_, weight) => Some(weight),
+                        Message::Terminate => None,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .fold((0, 0), |(n, total), weight| (n + 1, total + weight));
+        self.dispatcher
+            .counters
+            .lock()
+            .unwrap()
+            .dequeue(dropped, weight);
+        log::debug!("Discarded {} scheduled jobs.", dropped);
+
+        self.join_workers();
+        dropped
+    }
+
+    /// Stop waiting on the threads of this [ThreadPool](self::ThreadPool) once it is dropped,
+    /// leaving them to finish the work scheduled so far in the background, after which they exit.
+    ///
+    /// Useful to shut down without waiting on work which might take long to finish,
+    /// as dropping a pool otherwise blocks until all of its scheduled work is done.
+    pub fn detach(&self) {
+        self.detached.store(true, Ordering::SeqCst);
+    }
+
+    fn terminate_workers(&self) {
         log::debug!("Sending terminate message to all workers.");
 
-        for _ in &self.workers {
-            self.sender.send(Message::Terminate).unwrap();
+        for worker in &self.workers {
+            if worker.thread.is_some() {
+                self.dispatcher.send_to(worker.id, Message::Terminate);
+            }
         }
+    }
+
+    fn join_workers(&mut self) {
+        self.terminate_workers();
 
         log::debug!("Shutting down all workers.");
 
         for worker in &mut self.workers {
-            log::debug!("Shutting down worker {}", worker.id);
-
             if let Some(thread) = worker.thread.take() {
+                log::debug!("Shutting down worker {}", worker.id);
                 thread.join().unwrap();
             }
         }
     }
 }
 
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        if !self.detached.load(Ordering::SeqCst) {
+            self.join_workers();
+            return;
+        }
+        self.terminate_workers();
+        log::debug!("Detaching all workers.");
+        for worker in &mut self.workers {
+            worker.thread.take();
+        }
+    }
+}
+
 impl fmt::Debug for ThreadPool {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let size = self.workers.len();
@@ -153,27 +458,61 @@ impl fmt::Debug for ThreadPool {
     }
 }
 
+/// Handle to work scheduled using [ThreadPool::execute_interval](self::ThreadPool::execute_interval),
+/// which stops the work from being scheduled again once cancelled or dropped.
+///
+/// A run which was already scheduled at that point can still happen.
+pub struct IntervalHandle {
+    // dropping it disconnects the channel, stopping the timer
+    _stop: mpsc::Sender<()>,
+}
+
+impl IntervalHandle {
+    /// Stop the work from being scheduled again,
+    /// same as dropping this [IntervalHandle](self::IntervalHandle).
+    pub fn cancel(self) {}
+}
+
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
 enum Message {
-    NewJob(Job),
+    // a job together with its weight
+    NewJob(Job, usize),
     Terminate,
 }
 
 struct Worker {
     id: usize,
+    jobs: Arc<AtomicUsize>,
     thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
+    fn new(
+        id: usize,
+        receiver: Arc<Mutex<mpsc::Receiver<Message>>>,
+        counters: Arc<Mutex<Counters>>,
+    ) -> Worker {
+        let jobs = Arc::new(AtomicUsize::new(0));
+        let worker_jobs = Arc::clone(&jobs);
         let thread = thread::spawn(move || loop {
             let message = { receiver.lock().unwrap().recv().unwrap() };
 
             match message {
-                Message::NewJob(job) => {
+                Message::NewJob(job, weight) => {
                     log::debug!("Worker {} got a job; executing.", id);
+                    worker_jobs.fetch_add(1, Ordering::SeqCst);
+                    {
+                        let mut counters = counters.lock().unwrap();
+                        counters.dequeue(1, weight);
+                        counters.active += 1;
+                    }
                     job();
+                    {
+                        let mut counters = counters.lock().unwrap();
+                        counters.active -= 1;
+                        counters.completed_total += 1;
+                    }
                     log::debug!("Worker {} finished executing a job.", id);
                 }
                 Message::Terminate => {
@@ -185,6 +524,7 @@ impl Worker {
 
         Worker {
             id,
+            jobs,
             thread: Some(thread),
         }
     }
@@ -1,5 +1,9 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc;
-use webservice::thread::{Result, ThreadPool};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use webservice::thread::{PoolErrorKind, PoolStats, Result, ThreadPool};
 
 #[test]
 fn test_work_is_executed() -> Result<()> {
@@ -28,6 +32,213 @@ fn test_work_is_executed() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_shutdown_now_drops_scheduled_work() -> Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    let executed = Arc::new(AtomicUsize::new(0));
+
+    let pool = ThreadPool::new(1)?;
+    pool.execute(move || {
+        sender.send(()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+    });
+    // ensure the only worker is busy, such that all other work is queued
+    receiver.recv().unwrap();
+    for _ in 0..3 {
+        let executed = Arc::clone(&executed);
+        pool.execute(move || {
+            executed.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+
+    assert_eq!(3, pool.shutdown_now());
+    assert_eq!(0, executed.load(Ordering::SeqCst));
+
+    Ok(())
+}
+
+#[test]
+fn test_detach() -> Result<()> {
+    let (release_tx, release_rx) = mpsc::channel();
+    let (done_tx, done_rx) = mpsc::channel();
+
+    let pool = ThreadPool::new(1)?;
+    pool.execute(move || {
+        release_rx.recv().unwrap();
+        done_tx.send(()).unwrap();
+    });
+    pool.detach();
+    // returns without waiting on the work, which would never finish otherwise
+    drop(pool);
+
+    release_tx.send(()).unwrap();
+    done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    Ok(())
+}
+
+#[test]
+fn test_with_available_parallelism() -> Result<()> {
+    let pool = ThreadPool::with_available_parallelism()?;
+    let size = pool.stats().size;
+    assert!(size >= 1);
+    if let Ok(n) = thread::available_parallelism() {
+        assert_eq!(n.get(), size);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_round_robin_spreads_work_evenly() -> Result<()> {
+    const WORKERS: usize = 4;
+    const JOBS: usize = 400;
+
+    // difference between the most and least jobs executed by a worker of the pool,
+    // when submitting jobs of uneven duration all at once
+    let spread = |pool: ThreadPool| {
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..JOBS {
+            let sender = sender.clone();
+            pool.execute(move || {
+                thread::sleep(Duration::from_micros((i % 3) as u64 * 200));
+                sender.send(()).unwrap();
+            });
+        }
+        drop(sender);
+        assert_eq!(JOBS, receiver.iter().count());
+
+        let stats = pool.worker_stats();
+        assert_eq!(JOBS, stats.iter().sum::<usize>());
+        stats.iter().max().unwrap() - stats.iter().min().unwrap()
+    };
+    let shared = spread(ThreadPool::new(WORKERS)?);
+    let round_robin = spread(ThreadPool::new_round_robin(WORKERS)?);
+
+    // the shared queue leaves the spread up to the scheduler and the duration of the jobs,
+    // while round-robin dispatching spreads them evenly no matter either
+    assert!(round_robin <= JOBS / WORKERS / 10, "{}", round_robin);
+    assert!(round_robin <= shared, "{} > {}", round_robin, shared);
+
+    Ok(())
+}
+
+#[test]
+fn test_execute_interval() -> Result<()> {
+    let pool = ThreadPool::new(2)?;
+
+    let (sender, receiver) = mpsc::channel();
+    let handle = pool.execute_interval(Duration::from_millis(10), move || {
+        let _ = sender.send(());
+    });
+    for _ in 0..3 {
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+    handle.cancel();
+    // the sender is dropped together with the work once it stopped,
+    // after at most a run which was already scheduled
+    let late_runs = receiver.iter().count();
+    assert!(late_runs <= 1, "{}", late_runs);
+
+    // runs taking longer than the period are skipped rather than queued
+    let (started, starts) = mpsc::channel();
+    let (release, released) = mpsc::channel::<()>();
+    let running = Arc::new(AtomicBool::new(false));
+    let overlapped = Arc::new(AtomicBool::new(false));
+    let run_overlapped = Arc::clone(&overlapped);
+    let handle = pool.execute_interval(Duration::from_millis(10), move || {
+        if running.swap(true, Ordering::SeqCst) {
+            run_overlapped.store(true, Ordering::SeqCst);
+        }
+        let _ = started.send(());
+        // blocks until released, or returns right away once released for good
+        let _ = released.recv();
+        running.store(false, Ordering::SeqCst);
+    });
+    starts.recv_timeout(Duration::from_secs(5)).unwrap();
+    // let periods pass while the first run blocks
+    thread::sleep(Duration::from_millis(100));
+    assert!(starts.try_recv().is_err());
+    drop(release);
+    starts.recv_timeout(Duration::from_secs(5)).unwrap();
+    handle.cancel();
+    assert!(starts.iter().count() <= 1);
+    assert!(!overlapped.load(Ordering::SeqCst));
+
+    Ok(())
+}
+
+#[test]
+fn test_pool_stats() -> Result<()> {
+    const JOBS: usize = 6;
+
+    let pool = ThreadPool::new(2)?;
+    assert_eq!(
+        PoolStats {
+            size: 2,
+            active: 0,
+            queued: 0,
+            completed_total: 0,
+            peak_queue_depth: 0,
+        },
+        pool.stats()
+    );
+
+    for _ in 0..JOBS {
+        pool.execute(|| thread::sleep(Duration::from_millis(50)));
+    }
+    let stats = pool.stats();
+    assert!(stats.active <= stats.size);
+    assert_eq!(JOBS, stats.active + stats.queued + stats.completed_total);
+
+    let mut stats = pool.stats();
+    for _ in 0..100 {
+        if stats.completed_total == JOBS {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+        stats = pool.stats();
+    }
+    assert_eq!(JOBS, stats.completed_total);
+    assert_eq!(0, stats.active);
+    assert_eq!(0, stats.queued);
+    // at most two jobs could have been picked up before all were queued
+    assert!((JOBS - 2..=JOBS).contains(&stats.peak_queue_depth));
+
+    Ok(())
+}
+
+#[test]
+fn test_execute_weighted() -> Result<()> {
+    let mut pool = ThreadPool::new(1)?;
+    pool.set_max_queued_weight(10);
+
+    // keep the only worker busy, such that all weighted work remains queued
+    let (release, released) = mpsc::channel::<()>();
+    pool.execute(move || {
+        released.recv().unwrap();
+    });
+    while pool.stats().active == 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    pool.execute_weighted(6, || {})?;
+    pool.execute_weighted(4, || {})?;
+    assert_eq!(
+        PoolErrorKind::BudgetExceeded,
+        pool.execute_weighted(1, || {}).unwrap_err().kind
+    );
+    // unweighted work is never rejected
+    pool.execute(|| {});
+
+    release.send(()).unwrap();
+    while pool.stats().queued > 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    pool.execute_weighted(10, || {})?;
+
+    Ok(())
+}
+
 #[test]
 fn test_math_work_is_executed() -> Result<()> {
     assert_eq!(0, compute_math_in_parallel(1, 2, 1, 2)?);
@@ -53,6 +264,6 @@ fn compute_math_in_parallel(f1: i32, f2: i32, t1: i32, t2: i32) -> Result<i32> {
     drop(sender);
 
     let mut results: Vec<(i32, i32)> = receiver.iter().collect();
-    results.sort_by(|a, b| a.0.cmp(&b.0));
+    results.sort_by_key(|r| r.0);
     Ok(results[0].1 / results[1].1)
 }